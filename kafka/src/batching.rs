@@ -0,0 +1,535 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::join_all;
+use log::{debug, error};
+
+use crate::kafka_producer::{new_client_config, KafkaMessage, KafkaProducer};
+use crate::oauth::{OAuthClientContext, OAuthConfig};
+use crate::topics::{TopicAdmin, TopicCreationConfig};
+
+const COMPRESSION_TYPES: &[&str] = &["none", "gzip", "snappy", "lz4", "zstd"];
+
+/// Producer settings which can be overridden per record through metadata:
+/// - kafka.compression.type: none, gzip, snappy, lz4 or zstd
+/// - kafka.linger.ms: how long to accumulate records before sending a batch
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct TopicSettings {
+    pub compression_type: Option<String>,
+    pub linger_ms: Option<u64>,
+}
+
+impl TopicSettings {
+    pub fn from_metadata(mtd: &HashMap<String, String>) -> Result<Self, String> {
+        let compression_type = match mtd.get("kafka.compression.type") {
+            Some(c) if COMPRESSION_TYPES.contains(&c.as_str()) => Some(c.clone()),
+            Some(c) => return Err(format!("Unsupported compression type: '{}'. Expected one of: {}", c, COMPRESSION_TYPES.join(", "))),
+            None => None,
+        };
+        let linger_ms = match mtd.get("kafka.linger.ms") {
+            Some(l) => match l.parse::<u64>() {
+                Ok(l) => Some(l),
+                Err(e) => return Err(format!("Invalid value of 'kafka.linger.ms': '{}': {}", l, e)),
+            },
+            None => None,
+        };
+        Ok(TopicSettings {
+            compression_type,
+            linger_ms,
+        })
+    }
+}
+
+/// Reads the target partition from the 'kafka.partition' metadata. If not set, the partition is chosen by partitioner
+pub fn partition_from_metadata(mtd: &HashMap<String, String>) -> Result<Option<i32>, String> {
+    match mtd.get("kafka.partition") {
+        Some(p) => match p.parse::<i32>() {
+            Ok(p) => Ok(Some(p)),
+            Err(e) => Err(format!("Invalid partition number '{}': {}", p, e)),
+        },
+        None => Ok(None),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatchConfig {
+    /// A batch is sent once it has this number of records
+    pub max_records: usize,
+    /// Default linger time if the record has no 'kafka.linger.ms' metadata
+    pub linger_ms: u64,
+}
+
+impl BatchConfig {
+    /// Reads the batching settings from step parameters:
+    /// - batch.enabled: 'true' or 'false' (default)
+    /// - batch.max_records: 1000 by default
+    /// - batch.linger.ms: 5 by default
+    ///
+    /// Returns None if batching is disabled
+    pub fn from_step_params(params: &HashMap<String, String>) -> Result<Option<Self>, String> {
+        match params.get("batch.enabled").map(String::as_str) {
+            None | Some("false") => return Ok(None),
+            Some("true") => {},
+            Some(v) => return Err(format!("Invalid value of 'batch.enabled': '{}'. Expected 'true' or 'false'", v)),
+        };
+        let max_records = match params.get("batch.max_records") {
+            Some(v) => match v.parse::<usize>() {
+                Ok(0) => return Err(String::from("'batch.max_records' must be greater than zero")),
+                Ok(n) => n,
+                Err(e) => return Err(format!("Invalid value of 'batch.max_records': '{}': {}", v, e)),
+            },
+            None => 1000,
+        };
+        let linger_ms = match params.get("batch.linger.ms") {
+            Some(v) => v.parse::<u64>().map_err(|e| format!("Invalid value of 'batch.linger.ms': '{}': {}", v, e))?,
+            None => 5,
+        };
+        Ok(Some(BatchConfig {
+            max_records,
+            linger_ms,
+        }))
+    }
+}
+
+/// Records in the same batch share the topic, partition and producer settings
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct BatchKey {
+    pub topic: String,
+    pub partition: Option<i32>,
+    pub compression_type: Option<String>,
+    pub linger_ms: u64,
+}
+
+struct Batch {
+    created_at: Instant,
+    messages: Vec<KafkaMessage>,
+}
+
+/// Groups records by topic, partition and settings
+pub struct Accumulator {
+    cfg: BatchConfig,
+    batches: HashMap<BatchKey, Batch>,
+    /// Full batches which wait for the flushing thread
+    full_batches: Vec<(BatchKey, Vec<KafkaMessage>)>,
+    /// Is set once the step is stopped. The remaining batches are sent and the flushing thread exits
+    stopped: bool,
+}
+
+impl Accumulator {
+    pub fn new(cfg: BatchConfig) -> Self {
+        Accumulator {
+            cfg,
+            batches: HashMap::new(),
+            full_batches: Vec::new(),
+            stopped: false,
+        }
+    }
+
+    /// Adds a message to batch. Returns the batch if it is full
+    pub fn push(&mut self, msg: KafkaMessage, settings: TopicSettings) -> Option<(BatchKey, Vec<KafkaMessage>)> {
+        let key = BatchKey {
+            topic: msg.topic.clone(),
+            partition: msg.partition,
+            compression_type: settings.compression_type,
+            linger_ms: settings.linger_ms.unwrap_or(self.cfg.linger_ms),
+        };
+        let batch = self.batches.entry(key.clone()).or_insert_with(|| Batch {
+            created_at: Instant::now(),
+            messages: Vec::with_capacity(self.cfg.max_records),
+        });
+        batch.messages.push(msg);
+        if batch.messages.len() < self.cfg.max_records {
+            return None
+        }
+        self.batches.remove(&key).map(|b| (key, b.messages))
+    }
+
+    /// Removes and returns the batches which exceeded their linger time
+    pub fn take_expired(&mut self, now: Instant) -> Vec<(BatchKey, Vec<KafkaMessage>)> {
+        let expired: Vec<BatchKey> = self.batches
+            .iter()
+            .filter(|(k, b)| now.duration_since(b.created_at) >= Duration::from_millis(k.linger_ms))
+            .map(|(k, _)| k.clone())
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|k| self.batches.remove(&k).map(|b| (k, b.messages)))
+            .collect()
+    }
+
+    /// Removes and returns the full batches and the batches which exceeded their linger time
+    pub fn take_ready(&mut self, now: Instant) -> Vec<(BatchKey, Vec<KafkaMessage>)> {
+        let mut ready: Vec<(BatchKey, Vec<KafkaMessage>)> = self.full_batches.drain(..).collect();
+        ready.extend(self.take_expired(now));
+        ready
+    }
+
+    /// Removes and returns all batches regardless of their linger time
+    pub fn take_all(&mut self) -> Vec<(BatchKey, Vec<KafkaMessage>)> {
+        let mut all: Vec<(BatchKey, Vec<KafkaMessage>)> = self.full_batches.drain(..).collect();
+        all.extend(self.batches.drain().map(|(k, b)| (k, b.messages)));
+        all
+    }
+
+    /// The earliest time when some batch exceeds its linger time. None if there are no batches
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.batches
+            .iter()
+            .map(|(k, b)| b.created_at + Duration::from_millis(k.linger_ms))
+            .min()
+    }
+}
+
+/// Keeps one producer per compression type, because compression is configured per producer.
+/// The producers share one topic admin, so each topic is checked and created once
+struct ProducerPool {
+    driver_params: HashMap<String, String>,
+    topic_admin: Option<TopicAdmin>,
+    oauth_cfg: Option<OAuthConfig>,
+    producers: HashMap<Option<String>, KafkaProducer>,
+}
+
+impl ProducerPool {
+    fn get(&mut self, compression_type: &Option<String>) -> KafkaProducer {
+        if let Some(p) = self.producers.get(compression_type) {
+            return p.clone();
+        }
+
+        let mut driver_params = self.driver_params.clone();
+        if let Some(c) = compression_type {
+            driver_params.insert(String::from("compression.type"), c.clone());
+        }
+        let producer = KafkaProducer::with_topic_admin(&driver_params, self.topic_admin.clone(), self.oauth_cfg.clone());
+        self.producers.insert(compression_type.clone(), producer.clone());
+        producer
+    }
+}
+
+/// Accumulates records and sends them to Kafka in batches
+pub struct Batcher {
+    accumulator: Mutex<Accumulator>,
+    /// Wakes up the flushing thread when a batch is started or full, or the step is stopped
+    accumulator_changed: Condvar,
+    producers: Mutex<ProducerPool>,
+}
+
+impl Batcher {
+    pub fn new(cfg: BatchConfig, driver_params: HashMap<String, String>,
+            topic_creation_cfg: Option<TopicCreationConfig>, oauth_cfg: Option<OAuthConfig>) -> Self {
+        let topic_admin = topic_creation_cfg.map(|c| TopicAdmin::new(&new_client_config(&driver_params),
+            oauth_cfg.clone().map(OAuthClientContext::new), c));
+        Batcher {
+            accumulator: Mutex::new(Accumulator::new(cfg)),
+            accumulator_changed: Condvar::new(),
+            producers: Mutex::new(ProducerPool {
+                driver_params,
+                topic_admin,
+                oauth_cfg,
+                producers: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Adds a message to batch. A full batch is handed to the flushing thread, so the caller is not blocked.
+    /// If the batcher is stopped, the flushing thread has exited and the message is sent immediately
+    pub fn add(&self, msg: KafkaMessage, settings: TopicSettings) {
+        let stopped_batches = {
+            let mut acc = self.accumulator.lock().unwrap();
+            if let Some(full_batch) = acc.push(msg, settings) {
+                acc.full_batches.push(full_batch);
+            }
+            match acc.stopped {
+                true => acc.take_all(),
+                false => Vec::new(),
+            }
+        };
+        self.accumulator_changed.notify_one();
+        self.send(stopped_batches);
+    }
+
+    /// Makes the flushing thread send the remaining batches and exit
+    pub fn stop(&self) {
+        self.accumulator.lock().unwrap().stopped = true;
+        self.accumulator_changed.notify_one();
+    }
+
+    /// Sends the batches concurrently and waits until all of them are delivered
+    fn send(&self, batches: Vec<(BatchKey, Vec<KafkaMessage>)>) {
+        if batches.is_empty() {
+            return
+        }
+        let sends: Vec<_> = batches.into_iter().map(|(key, messages)| {
+            let producer = self.producers.lock().unwrap().get(&key.compression_type);
+            async move {
+                let results = producer.produce_batch(&messages).await;
+                let num_failed = results.iter().filter(|r| r.is_err()).count();
+                debug!("Sent a batch of {} records to topic '{}' (partition: {:?}, compression: {}, linger: {} ms). Failed: {}",
+                    messages.len(), key.topic, key.partition, key.compression_type.as_deref().unwrap_or("default"),
+                    key.linger_ms, num_failed);
+                for e in results.into_iter().filter_map(Result::err) {
+                    error!("Failed to send a message to Kafka topic '{}': {}", key.topic, e);
+                }
+            }
+        }).collect();
+        futures::executor::block_on(join_all(sends));
+    }
+}
+
+/// A thread routine which sends the batches once they are full or their linger time is over.
+/// Sleeps until the earliest batch deadline. Once the batcher is stopped, sends the remaining batches and exits
+pub fn thread_flush(batcher: Arc<Batcher>) {
+    loop {
+        let (batches, stopped) = {
+            let mut acc = batcher.accumulator.lock().unwrap();
+            loop {
+                if acc.stopped {
+                    break (acc.take_all(), true);
+                }
+                let now = Instant::now();
+                let ready = acc.take_ready(now);
+                if !ready.is_empty() {
+                    break (ready, false);
+                }
+                acc = match acc.next_deadline() {
+                    Some(d) => batcher.accumulator_changed.wait_timeout(acc, d.saturating_duration_since(now)).unwrap().0,
+                    None => batcher.accumulator_changed.wait(acc).unwrap(),
+                };
+            }
+        };
+        batcher.send(batches);
+        if stopped {
+            return
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use rdkafka::config::ClientConfig;
+    use rdkafka::consumer::{BaseConsumer, Consumer};
+    use rdkafka::message::Message;
+    use rdkafka::mocking::MockCluster;
+
+    use crate::kafka_producer::KafkaMessage;
+    use crate::test_utils::to_hashmap;
+
+    use super::{partition_from_metadata, thread_flush, Accumulator, BatchConfig, Batcher, TopicSettings};
+
+    const TOPIC: &str = "torustiq_batching_test";
+
+    fn new_message(topic: &str, partition: Option<i32>) -> KafkaMessage {
+        KafkaMessage {
+            topic: String::from(topic),
+            partition,
+            key: None,
+            headers: HashMap::new(),
+            payload: vec![],
+        }
+    }
+
+    fn new_payload_message(partition: i32, payload: &str) -> KafkaMessage {
+        KafkaMessage {
+            payload: payload.as_bytes().to_vec(),
+            ..new_message(TOPIC, Some(partition))
+        }
+    }
+
+    /// Returns the payloads of the next `count` messages, sorted
+    fn consume(consumer: &BaseConsumer, count: usize) -> Vec<String> {
+        let mut payloads = Vec::new();
+        for _ in 0..50 {
+            if payloads.len() == count {
+                break
+            }
+            match consumer.poll(Duration::from_millis(200)) {
+                Some(Ok(m)) => payloads.push(String::from_utf8(m.payload().unwrap_or_default().to_vec()).unwrap()),
+                Some(Err(e)) => panic!("Failed to consume a message: {}", e),
+                None => continue,
+            }
+        }
+        payloads.sort();
+        payloads
+    }
+
+    fn new_accumulator(max_records: usize, linger_ms: u64) -> Accumulator {
+        Accumulator::new(BatchConfig {
+            max_records,
+            linger_ms,
+        })
+    }
+
+    #[test]
+    fn test_topic_settings_from_metadata() {
+        assert_eq!(TopicSettings::from_metadata(&to_hashmap(&[
+            ("kafka.compression.type", "zstd"),
+            ("kafka.linger.ms", "50"),
+        ])), Ok(TopicSettings {
+            compression_type: Some(String::from("zstd")),
+            linger_ms: Some(50),
+        }));
+        assert_eq!(TopicSettings::from_metadata(&HashMap::new()), Ok(TopicSettings::default()));
+        assert!(TopicSettings::from_metadata(&to_hashmap(&[("kafka.compression.type", "brotli")])).is_err());
+        assert!(TopicSettings::from_metadata(&to_hashmap(&[("kafka.linger.ms", "-1")])).is_err());
+    }
+
+    #[test]
+    fn test_batch_config_from_step_params() {
+        assert_eq!(BatchConfig::from_step_params(&HashMap::new()), Ok(None));
+        assert_eq!(BatchConfig::from_step_params(&to_hashmap(&[("batch.enabled", "true")])),
+            Ok(Some(BatchConfig { max_records: 1000, linger_ms: 5 })));
+        assert_eq!(BatchConfig::from_step_params(&to_hashmap(&[
+            ("batch.enabled", "true"),
+            ("batch.max_records", "10"),
+            ("batch.linger.ms", "100"),
+        ])), Ok(Some(BatchConfig { max_records: 10, linger_ms: 100 })));
+        assert!(BatchConfig::from_step_params(&to_hashmap(&[
+            ("batch.enabled", "true"),
+            ("batch.max_records", "0"),
+        ])).is_err());
+    }
+
+    #[test]
+    fn test_accumulator_full_batch() {
+        let mut acc = new_accumulator(2, 1000);
+        assert!(acc.push(new_message("a", None), TopicSettings::default()).is_none());
+        assert!(acc.push(new_message("b", None), TopicSettings::default()).is_none());
+        assert!(acc.push(new_message("a", Some(1)), TopicSettings::default()).is_none());
+
+        let (key, messages) = acc.push(new_message("a", None), TopicSettings::default())
+            .expect("The batch must be full");
+        assert_eq!(key.topic, "a");
+        assert_eq!(key.partition, None);
+        assert_eq!(key.linger_ms, 1000);
+        assert_eq!(messages.len(), 2);
+
+        // A new batch is started for the same key
+        assert!(acc.push(new_message("a", None), TopicSettings::default()).is_none());
+    }
+
+    #[test]
+    fn test_accumulator_groups_by_settings() {
+        let mut acc = new_accumulator(2, 1000);
+        let gzip = TopicSettings {
+            compression_type: Some(String::from("gzip")),
+            linger_ms: None,
+        };
+        assert!(acc.push(new_message("a", None), TopicSettings::default()).is_none());
+        assert!(acc.push(new_message("a", None), gzip.clone()).is_none());
+
+        let (key, _) = acc.push(new_message("a", None), gzip).expect("The batch must be full");
+        assert_eq!(key.compression_type, Some(String::from("gzip")));
+    }
+
+    #[test]
+    fn test_accumulator_take_expired() {
+        let mut acc = new_accumulator(100, 1000);
+        let low_latency = TopicSettings {
+            compression_type: None,
+            linger_ms: Some(0),
+        };
+        acc.push(new_message("slow", None), TopicSettings::default());
+        acc.push(new_message("fast", None), low_latency.clone());
+        acc.push(new_message("fast", None), low_latency);
+
+        let expired = acc.take_expired(Instant::now());
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].0.topic, "fast");
+        assert_eq!(expired[0].1.len(), 2);
+
+        let expired = acc.take_expired(Instant::now() + Duration::from_secs(2));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].0.topic, "slow");
+        assert!(acc.take_expired(Instant::now() + Duration::from_secs(2)).is_empty());
+    }
+
+    #[test]
+    fn test_accumulator_take_ready() {
+        let mut acc = new_accumulator(2, 1000);
+        acc.push(new_message("full", None), TopicSettings::default());
+        let full_batch = acc.push(new_message("full", None), TopicSettings::default())
+            .expect("The batch must be full");
+        acc.full_batches.push(full_batch);
+        acc.push(new_message("slow", None), TopicSettings::default());
+
+        let ready = acc.take_ready(Instant::now());
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].0.topic, "full");
+        assert!(acc.full_batches.is_empty());
+
+        acc.full_batches.push(ready.into_iter().next().unwrap());
+        assert_eq!(acc.take_all().len(), 2);
+        assert!(acc.full_batches.is_empty());
+    }
+
+    #[test]
+    fn test_partition_from_metadata() {
+        assert_eq!(partition_from_metadata(&to_hashmap(&[("kafka.partition", "3")])), Ok(Some(3)));
+        assert_eq!(partition_from_metadata(&HashMap::new()), Ok(None));
+        assert!(partition_from_metadata(&to_hashmap(&[("kafka.partition", "first")])).is_err());
+    }
+
+    #[test]
+    fn test_accumulator_next_deadline() {
+        let mut acc = new_accumulator(100, 1000);
+        assert_eq!(acc.next_deadline(), None);
+        let before = Instant::now();
+        acc.push(new_message("slow", None), TopicSettings::default());
+        acc.push(new_message("fast", None), TopicSettings {
+            compression_type: None,
+            linger_ms: Some(10),
+        });
+        let deadline = acc.next_deadline().expect("There must be a deadline");
+        assert!(deadline >= before + Duration::from_millis(10) && deadline < before + Duration::from_millis(1000));
+
+        assert_eq!(acc.take_all().len(), 2);
+        assert_eq!(acc.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_batcher() {
+        let mock_cluster = MockCluster::new(3).expect("Can't create a mock Kafka cluster");
+        mock_cluster.create_topic(TOPIC, 2, 1).expect("Can't create a test topic");
+        let bootstrap_servers = mock_cluster.bootstrap_servers();
+        let batcher = Arc::new(Batcher::new(BatchConfig { max_records: 2, linger_ms: 50 }, to_hashmap(&[
+            ("bootstrap.servers", bootstrap_servers.as_str()),
+            ("message.timeout.ms", "5000"),
+        ]), None, None));
+        let consumer: BaseConsumer = ClientConfig::new()
+            .set("bootstrap.servers", bootstrap_servers.as_str())
+            .set("group.id", "torustiq_test")
+            .set("auto.offset.reset", "earliest")
+            .create()
+            .expect("Can't create a Kafka consumer");
+        consumer.subscribe(&[TOPIC]).expect("Can't subscribe to the test topic");
+
+        // The full batch is left to the flushing thread instead of being sent by the caller
+        batcher.add(new_payload_message(0, "1"), TopicSettings::default());
+        batcher.add(new_payload_message(0, "2"), TopicSettings::default());
+        assert_eq!(batcher.accumulator.lock().unwrap().full_batches.len(), 1);
+
+        let batcher_thread = batcher.clone();
+        let flush_thread = thread::spawn(move || thread_flush(batcher_thread));
+        // The incomplete batch is sent once its linger time is over
+        batcher.add(new_payload_message(1, "3"), TopicSettings::default());
+        assert_eq!(consume(&consumer, 3), vec!["1", "2", "3"]);
+
+        // The remaining batches are sent on stop regardless of their linger time
+        batcher.add(new_payload_message(1, "4"), TopicSettings {
+            compression_type: Some(String::from("gzip")),
+            linger_ms: Some(60_000),
+        });
+        batcher.stop();
+        flush_thread.join().expect("The flushing thread has panicked");
+        assert!(batcher.accumulator.lock().unwrap().batches.is_empty());
+
+        // Once the flushing thread has exited, messages are sent immediately
+        batcher.add(new_payload_message(0, "5"), TopicSettings::default());
+        assert_eq!(consume(&consumer, 2), vec!["4", "5"]);
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+
+use rdkafka::config::ClientConfig;
+use rdkafka::error::KafkaError;
+use rdkafka::types::RDKafkaConfRes;
+
+/// Driver parameter which points to a file with librdkafka properties
+const CONFIG_FILE_PARAM: &str = "config_file";
+
+/// Builds a librdkafka configuration from driver parameters (i.e. step parameters without the 'driver.' prefix):
+/// - properties from 'config_file' are loaded first, other parameters override them
+/// - keys must be librdkafka properties, either as is ('security.protocol', 'log_level')
+///   or with underscores in place of dots ('bootstrap_servers' -> 'bootstrap.servers')
+/// - '${ENV_VAR}' in values is replaced with a value of environment variable
+///
+/// Every property is checked by librdkafka, so unknown names and invalid values are reported before clients are created
+pub fn build_driver_config(params: &HashMap<String, String>) -> Result<HashMap<String, String>, String> {
+    let mut raw_config = match params.get(CONFIG_FILE_PARAM) {
+        Some(path) => {
+            let path = substitute_env_vars(path)?;
+            match fs::read_to_string(&path) {
+                Ok(c) => parse_properties(&c)?,
+                Err(e) => return Err(format!("Failed to read the Kafka config file '{}': {}", path, e)),
+            }
+        },
+        None => HashMap::new(),
+    };
+    raw_config.extend(params
+        .iter()
+        .filter(|(k, _)| k.as_str() != CONFIG_FILE_PARAM)
+        .map(|(k, v)| (k.clone(), v.clone())));
+
+    let mut config = HashMap::with_capacity(raw_config.len());
+    for (k, v) in raw_config {
+        let value = match substitute_env_vars(&v) {
+            Ok(v) => v,
+            Err(e) => return Err(format!("Failed to substitute environment variables in Kafka property '{}': {}", k, e)),
+        };
+        config.insert(map_property_name(&k, &value)?, value);
+    }
+    Ok(config)
+}
+
+/// Maps a parameter name to librdkafka property name. The name is taken as is if librdkafka knows it,
+/// otherwise underscores are replaced with dots
+fn map_property_name(k: &str, v: &str) -> Result<String, String> {
+    let dotted = k.replace('_', ".");
+    let result = match check_property(k, v) {
+        Err(KafkaError::ClientConfig(RDKafkaConfRes::RD_KAFKA_CONF_UNKNOWN, ..)) if dotted != k => {
+            check_property(&dotted, v).map(|_| dotted)
+        },
+        r => r.map(|_| String::from(k)),
+    };
+    match result {
+        Ok(name) => Ok(name),
+        Err(KafkaError::ClientConfig(RDKafkaConfRes::RD_KAFKA_CONF_UNKNOWN, ..)) => Err(format!(
+            "Unknown Kafka property '{}'. Use the dotted librdkafka property name, e.g. 'bootstrap.servers'", k)),
+        Err(KafkaError::ClientConfig(_, desc, _, _)) => Err(format!("Invalid Kafka property '{}': {}", k, desc)),
+        Err(e) => Err(format!("Invalid Kafka property '{}': {}", k, e)),
+    }
+}
+
+/// Sets the property in a native librdkafka configuration, which fails on unknown names and invalid values
+fn check_property(k: &str, v: &str) -> Result<(), KafkaError> {
+    ClientConfig::new().set(k, v).create_native_config().map(|_| ())
+}
+
+/// Parses the contents of Java-style properties file: 'key=value' or 'key: value' lines.
+/// Lines starting with '#' or '!' are comments
+fn parse_properties(contents: &str) -> Result<HashMap<String, String>, String> {
+    let mut properties = HashMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with('!') {
+            continue;
+        }
+        let pos = match line.find(|c| c == '=' || c == ':') {
+            Some(p) => p,
+            None => return Err(format!("Invalid line {} in Kafka config file: expected 'key=value'", i + 1)),
+        };
+        properties.insert(String::from(line[..pos].trim()), String::from(line[pos + 1..].trim()));
+    }
+    Ok(properties)
+}
+
+/// Replaces '${ENV_VAR}' placeholders with values of environment variables
+fn substitute_env_vars(value: &str) -> Result<String, String> {
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let end = match rest[start..].find('}') {
+            Some(e) => start + e,
+            None => return Err(format!("Unclosed '${{' in value '{}'", value)),
+        };
+        let var_name = &rest[start + 2..end];
+        match env::var(var_name) {
+            Ok(v) => {
+                result.push_str(&rest[..start]);
+                result.push_str(&v);
+            },
+            Err(e) => return Err(format!("Environment variable '{}' is not available: {}", var_name, e)),
+        };
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use crate::test_utils::{to_hashmap, TempDir};
+
+    use super::{build_driver_config, parse_properties, substitute_env_vars};
+
+    #[test]
+    fn test_build_driver_config() {
+        let config = build_driver_config(&to_hashmap(&[
+            ("bootstrap_servers", "localhost:9092"),
+            ("sasl.oauthbearer.client.id", "my_client"),
+            ("ssl.endpoint.identification.algorithm", "none"),
+            ("log_level", "7"),
+            ("acks", "all"),
+        ])).unwrap();
+
+        assert_eq!(config, to_hashmap(&[
+            ("bootstrap.servers", "localhost:9092"),
+            ("sasl.oauthbearer.client.id", "my_client"),
+            ("ssl.endpoint.identification.algorithm", "none"),
+            ("log_level", "7"),
+            ("acks", "all"),
+        ]));
+    }
+
+    #[test]
+    fn test_build_driver_config_unknown_property() {
+        let err = build_driver_config(&to_hashmap(&[("bootstrap_server", "localhost:9092")])).unwrap_err();
+        assert!(err.contains("'bootstrap_server'"), "Unexpected error: {}", err);
+        // Dotted names are checked as well
+        let err = build_driver_config(&to_hashmap(&[("security.protocool", "plaintext")])).unwrap_err();
+        assert!(err.contains("'security.protocool'"), "Unexpected error: {}", err);
+    }
+
+    #[test]
+    fn test_build_driver_config_invalid_value() {
+        let err = build_driver_config(&to_hashmap(&[("message_timeout_ms", "not a number")])).unwrap_err();
+        assert!(err.contains("Invalid Kafka property 'message_timeout_ms'"), "Unexpected error: {}", err);
+        assert!(build_driver_config(&to_hashmap(&[("security.protocol", "carrier_pigeon")])).is_err());
+    }
+
+    #[test]
+    fn test_build_driver_config_file() {
+        env::set_var("TORUSTIQ_KAFKA_TEST_PASSWORD", "secret");
+        let dir = TempDir::new("driver_config");
+        let path = dir.join("kafka.properties");
+        fs::write(&path, "# Kafka config\n\
+            bootstrap.servers=broker1:9092\n\
+            sasl.username = user\n\
+            sasl.password=${TORUSTIQ_KAFKA_TEST_PASSWORD}\n\
+            \n\
+            client.id: from_file\n").unwrap();
+
+        let config = build_driver_config(&to_hashmap(&[
+            ("config_file", path.to_str().unwrap()),
+            ("client.id", "from_params"),
+        ])).unwrap();
+
+        assert_eq!(config, to_hashmap(&[
+            ("bootstrap.servers", "broker1:9092"),
+            ("sasl.username", "user"),
+            ("sasl.password", "secret"),
+            ("client.id", "from_params"),
+        ]));
+    }
+
+    #[test]
+    fn test_build_driver_config_missing_file() {
+        assert!(build_driver_config(&to_hashmap(&[("config_file", "/nonexistent/kafka.properties")])).is_err());
+    }
+
+    #[test]
+    fn test_parse_properties_invalid_line() {
+        assert!(parse_properties("bootstrap.servers=localhost:9092\ninvalid line").is_err());
+    }
+
+    #[test]
+    fn test_substitute_env_vars() {
+        env::set_var("TORUSTIQ_KAFKA_TEST_USER", "alice");
+        env::remove_var("TORUSTIQ_KAFKA_TEST_MISSING");
+
+        assert_eq!(substitute_env_vars("plain value"), Ok(String::from("plain value")));
+        assert_eq!(substitute_env_vars("user=${TORUSTIQ_KAFKA_TEST_USER};${TORUSTIQ_KAFKA_TEST_USER}"),
+            Ok(String::from("user=alice;alice")));
+        assert!(substitute_env_vars("${TORUSTIQ_KAFKA_TEST_MISSING}").is_err());
+        assert!(substitute_env_vars("${TORUSTIQ_KAFKA_TEST_USER").is_err());
+    }
+}
@@ -1,9 +1,14 @@
 use std::collections::HashMap;
 
+use futures::future::join_all;
 use log::{error, info};
+use rdkafka::client::DefaultClientContext;
 use rdkafka::config::ClientConfig;
 use rdkafka::message::{Header, OwnedHeaders};
-use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::producer::{DeliveryFuture, FutureProducer, FutureRecord};
+
+use crate::oauth::{OAuthClientContext, OAuthConfig};
+use crate::topics::{TopicAdmin, TopicCreationConfig};
 
 
 fn queue_poll_error_cb(message: String) {
@@ -13,41 +18,101 @@ fn queue_poll_error_cb(message: String) {
     }
 }
 
+/// The custom context is only used if OAUTHBEARER tokens are provided by the module.
+/// Otherwise librdkafka handles the SASL settings itself
+#[derive(Clone)]
+enum RdProducer {
+    Default(FutureProducer<DefaultClientContext>),
+    OAuth(FutureProducer<OAuthClientContext>),
+}
+
 #[derive(Clone)]
 pub struct KafkaProducer {
-    rd_producer: FutureProducer,
+    rd_producer: RdProducer,
+    /// Creates missing topics if automatic creation of topics is enabled
+    topic_admin: Option<TopicAdmin>,
 }
 
 pub struct KafkaMessage {
     pub topic: String,
+    /// If not set, the partition is chosen by partitioner
+    pub partition: Option<i32>,
     pub key: Option<String>,
     pub headers: HashMap<String, String>,
     pub payload: Vec<u8>,
 }
 
+/// Builds the client config from librdkafka properties, see `driver_config::build_driver_config`
+pub fn new_client_config(cfg: &HashMap<String, String>) -> ClientConfig {
+    let mut kafka_config = ClientConfig::new();
+    for (k, v) in cfg.iter() {
+        kafka_config.set(k, v);
+    }
+    kafka_config.set_queue_poll_error_cb(queue_poll_error_cb);
+    kafka_config
+}
+
 impl KafkaProducer {
-    pub fn new(cfg: &HashMap<String, String>) -> Self {
-        let bootstrap_servers = cfg.get("bootstrap_servers")
+    /// Creates a producer. The config must contain librdkafka property names, see `driver_config::build_driver_config`
+    pub fn new(cfg: &HashMap<String, String>, topic_creation_cfg: Option<TopicCreationConfig>,
+            oauth_cfg: Option<OAuthConfig>) -> Self {
+        let topic_admin = topic_creation_cfg.map(|c| TopicAdmin::new(&new_client_config(cfg),
+            oauth_cfg.clone().map(OAuthClientContext::new), c));
+        KafkaProducer::with_topic_admin(cfg, topic_admin, oauth_cfg)
+    }
+
+    /// Creates a producer which uses the given topic admin. Clones of the admin share the admin client
+    /// and the cache of known topics, so several producers can check and create topics only once
+    pub fn with_topic_admin(cfg: &HashMap<String, String>, topic_admin: Option<TopicAdmin>,
+            oauth_cfg: Option<OAuthConfig>) -> Self {
+        let bootstrap_servers = cfg.get("bootstrap.servers")
             .unwrap_or(&String::from("(not provided)"))
             .clone();  // to resolve a '&String vs String' issue
         info!("Bootstrap servers: {}", bootstrap_servers);
 
-        let mut kafka_config = &mut ClientConfig::new();
-        for (k, v) in cfg.iter() {
-            kafka_config = kafka_config.set(k.replace("_", "."), v);
-        }
-        kafka_config = kafka_config.set_queue_poll_error_cb(queue_poll_error_cb);
+        let kafka_config = new_client_config(cfg);
+        let rd_producer = match oauth_cfg {
+            Some(c) => RdProducer::OAuth(kafka_config.create_with_context(OAuthClientContext::new(c))
+                .expect("Can't create a Kafka producer")),
+            None => RdProducer::Default(kafka_config.create().expect("Can't create a Kafka producer")),
+        };
 
         KafkaProducer {
-            rd_producer: kafka_config.create().expect("Can't create a Kafka producer")
+            rd_producer,
+            topic_admin,
         }
     }
 
     // On success returns a tuple (partition, offset)
     // On failure returns an error message
     pub async fn produce(&self, msg: &KafkaMessage) -> Result<(i32, i64), String> {
-        let p = &msg.payload.to_vec();
+        if let Some(topic_admin) = &self.topic_admin {
+            topic_admin.ensure_topic(&msg.topic).await?;
+        }
+
+        wait_delivery(self.enqueue(msg)?).await
+    }
+
+    /// Enqueues all messages before waiting for delivery, so librdkafka can send them in the same request.
+    /// Messages are expected to have the same topic. Results are returned in the order of messages
+    pub async fn produce_batch(&self, msgs: &[KafkaMessage]) -> Vec<Result<(i32, i64), String>> {
+        if let (Some(topic_admin), Some(msg)) = (&self.topic_admin, msgs.first()) {
+            if let Err(e) = topic_admin.ensure_topic(&msg.topic).await {
+                return msgs.iter().map(|_| Err(e.clone())).collect();
+            }
+        }
+
+        let deliveries: Vec<Result<DeliveryFuture, String>> = msgs.iter().map(|m| self.enqueue(m)).collect();
+        join_all(deliveries.into_iter().map(|d| async move {
+            match d {
+                Ok(f) => wait_delivery(f).await,
+                Err(e) => Err(e),
+            }
+        })).await
+    }
 
+    /// Passes a message to librdkafka queue
+    fn enqueue(&self, msg: &KafkaMessage) -> Result<DeliveryFuture, String> {
         let mut headers = OwnedHeaders::new_with_capacity(msg.headers.len());
         for (key, value) in &msg.headers {
             headers = headers.insert(Header{key, value: Some(value)});
@@ -55,24 +120,167 @@ impl KafkaProducer {
 
         // Init a message with payload
         let mut future_record: FutureRecord<'_, String, Vec<u8>> = FutureRecord::to(&msg.topic)
-            .payload(p)
+            .payload(&msg.payload)
             .headers(headers);
         future_record = match &msg.key {
             Some(k) => future_record.key(k),
             None => future_record,
         };
+        future_record = match msg.partition {
+            Some(p) => future_record.partition(p),
+            None => future_record,
+        };
 
-
-        let res = match self.rd_producer.send_result(future_record) {
-            Ok(o) => o,
-            Err((kafka_error, _)) => return Err(format!("{:?}", kafka_error)),
+        let result = match &self.rd_producer {
+            RdProducer::Default(p) => p.send_result(future_record),
+            RdProducer::OAuth(p) => p.send_result(future_record),
         };
-        match res.await {
-            Ok(res2) => match res2 {
-                Ok(d) => Ok(d),
-                Err((kafka_error, _)) => Err(format!("{:?}", kafka_error))
-            },
-            Err(_) => Err(format!("An error occurred")),
+        match result {
+            Ok(o) => Ok(o),
+            Err((kafka_error, _)) => Err(format!("{:?}", kafka_error)),
+        }
+    }
+}
+
+async fn wait_delivery(delivery: DeliveryFuture) -> Result<(i32, i64), String> {
+    match delivery.await {
+        Ok(res) => match res {
+            Ok(d) => Ok(d),
+            Err((kafka_error, _)) => Err(format!("{:?}", kafka_error))
+        },
+        Err(_) => Err(format!("An error occurred")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use rdkafka::config::ClientConfig;
+    use rdkafka::consumer::{BaseConsumer, Consumer};
+    use rdkafka::message::{Headers, Message, OwnedMessage};
+    use rdkafka::mocking::MockCluster;
+    use rdkafka::producer::DefaultProducerContext;
+    use rdkafka::types::{RDKafkaApiKey, RDKafkaRespErr};
+
+    use super::{KafkaMessage, KafkaProducer};
+
+    const TOPIC: &str = "torustiq_test";
+
+    fn new_mock_cluster() -> MockCluster<'static, DefaultProducerContext> {
+        MockCluster::new(3).expect("Can't create a mock Kafka cluster")
+    }
+
+    fn new_producer(mock_cluster: &MockCluster<'static, DefaultProducerContext>) -> KafkaProducer {
+        KafkaProducer::new(&HashMap::from([
+            (String::from("bootstrap.servers"), mock_cluster.bootstrap_servers()),
+            (String::from("message.timeout.ms"), String::from("5000")),
+        ]), None, None)
+    }
+
+    fn new_message(key: Option<&str>, headers: &[(&str, &str)]) -> KafkaMessage {
+        KafkaMessage {
+            topic: String::from(TOPIC),
+            partition: None,
+            key: key.map(String::from),
+            headers: headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            payload: b"Hello, Kafka!".to_vec(),
+        }
+    }
+
+    fn consume_one(mock_cluster: &MockCluster<'static, DefaultProducerContext>) -> OwnedMessage {
+        let consumer: BaseConsumer = ClientConfig::new()
+            .set("bootstrap.servers", mock_cluster.bootstrap_servers())
+            .set("group.id", "torustiq_test")
+            .set("auto.offset.reset", "earliest")
+            .create()
+            .expect("Can't create a Kafka consumer");
+        consumer.subscribe(&[TOPIC]).expect("Can't subscribe to the test topic");
+
+        for _ in 0..50 {
+            match consumer.poll(Duration::from_millis(200)) {
+                Some(Ok(m)) => return m.detach(),
+                Some(Err(e)) => panic!("Failed to consume a message: {}", e),
+                None => continue,
+            }
+        }
+        panic!("No message received from topic '{}'", TOPIC)
+    }
+
+    #[test]
+    fn test_produce() {
+        let mock_cluster = new_mock_cluster();
+        let producer = new_producer(&mock_cluster);
+
+        let msg = new_message(Some("key-1"), &[("content-type", "text/plain"), ("trace.id", "abc")]);
+        let (partition, offset) = futures::executor::block_on(producer.produce(&msg))
+            .expect("Failed to produce a message");
+        assert!(partition >= 0);
+        assert_eq!(offset, 0);
+
+        let consumed = consume_one(&mock_cluster);
+        assert_eq!(consumed.topic(), TOPIC);
+        assert_eq!(consumed.partition(), partition);
+        assert_eq!(consumed.key(), Some("key-1".as_bytes()));
+        assert_eq!(consumed.payload(), Some(msg.payload.as_slice()));
+
+        let headers: HashMap<String, String> = consumed.headers()
+            .expect("Headers are missing in the consumed message")
+            .iter()
+            .map(|h| (h.key.to_string(), String::from_utf8(h.value.unwrap().to_vec()).unwrap()))
+            .collect();
+        assert_eq!(headers, msg.headers);
+    }
+
+    #[test]
+    fn test_produce_without_key() {
+        let mock_cluster = new_mock_cluster();
+        let producer = new_producer(&mock_cluster);
+
+        futures::executor::block_on(producer.produce(&new_message(None, &[])))
+            .expect("Failed to produce a message");
+
+        let consumed = consume_one(&mock_cluster);
+        assert_eq!(consumed.key(), None);
+        assert_eq!(consumed.headers().map(|h| h.count()).unwrap_or(0), 0);
+    }
+
+    #[test]
+    fn test_produce_broker_error() {
+        let mock_cluster = new_mock_cluster();
+        let producer = new_producer(&mock_cluster);
+        mock_cluster.request_errors(RDKafkaApiKey::Produce, &[RDKafkaRespErr::RD_KAFKA_RESP_ERR_MSG_SIZE_TOO_LARGE]);
+
+        let err = futures::executor::block_on(producer.produce(&new_message(None, &[])))
+            .expect_err("Produce must fail on a broker error");
+        assert!(err.contains("MessageSizeTooLarge"), "Unexpected error: {}", err);
+    }
+
+    #[test]
+    fn test_produce_batch() {
+        let mock_cluster = new_mock_cluster();
+        mock_cluster.create_topic(TOPIC, 2, 1).expect("Can't create a test topic");
+        let producer = KafkaProducer::new(&HashMap::from([
+            (String::from("bootstrap.servers"), mock_cluster.bootstrap_servers()),
+            (String::from("compression.type"), String::from("gzip")),
+        ]), None, None);
+
+        let mut msgs: Vec<KafkaMessage> = (0..10).map(|_| new_message(None, &[])).collect();
+        msgs.iter_mut().for_each(|m| m.partition = Some(1));
+        let results = futures::executor::block_on(producer.produce_batch(&msgs));
+
+        assert_eq!(results.len(), 10);
+        for (i, res) in results.into_iter().enumerate() {
+            assert_eq!(res, Ok((1, i as i64)));
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    #[should_panic(expected = "Can't create a Kafka producer")]
+    fn test_new_invalid_config() {
+        KafkaProducer::new(&HashMap::from([
+            (String::from("message.timeout.ms"), String::from("not a number")),
+        ]), None, None);
+    }
+}
@@ -1,8 +1,15 @@
+mod batching;
+mod driver_config;
 mod kafka_producer;
+mod oauth;
+#[cfg(test)]
+mod test_utils;
+mod topics;
 
 use std::{
     collections::HashMap, 
-    sync::Mutex
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle}
 };
 
 use kafka_producer::KafkaMessage;
@@ -14,17 +21,27 @@ use torustiq_common::{
         shared::{
             get_params, get_pipeline_module_configuration, set_pipeline_lib_configuration, set_pipeline_module_configuration
         },
-        types::{
-            module as module_types,
-            std_types::ConstCStrPtr
+        types::module::{
+            self as module_types, LibInfo, ModuleHandle, ModulePipelineConfigureArgs, ModulePipelineConfigureFnResult,
+            ModulePipelineProcessRecordFnResult, PipelineModuleKind, Record, StepStartFnResult
+        },
+        utils::strings::string_to_cchar
     },
-    utils::strings::string_to_cchar},
-    logging::init_logger
+    logging::init_logger,
+    CURRENT_API_VERSION
+};
+use crate::{
+    batching::{partition_from_metadata, thread_flush, BatchConfig, Batcher, TopicSettings},
+    driver_config::build_driver_config,
+    kafka_producer::KafkaProducer,
+    oauth::OAuthConfig,
+    topics::TopicCreationConfig
 };
-use crate::kafka_producer::KafkaProducer;
 
 const MODULE_INFO: LibInfo = LibInfo {
+    api_version: CURRENT_API_VERSION,
     id: c"kafka".as_ptr(),
+    kind: module_types::ModuleKind::Pipeline,
     name: c"Kafka output".as_ptr(),
 };
 
@@ -32,6 +49,48 @@ static PRODUCER: Lazy<Mutex<Option<KafkaProducer>>> = Lazy::new(|| {
     Mutex::new(None)
 });
 
+/// Is set instead of PRODUCER if batching is enabled
+static BATCHER: Lazy<Mutex<Option<Arc<Batcher>>>> = Lazy::new(|| {
+    Mutex::new(None)
+});
+
+/// The thread which sends batches once their linger time is over. Exits after the batcher is stopped
+static FLUSH_THREAD: Lazy<Mutex<Option<JoinHandle<()>>>> = Lazy::new(|| {
+    Mutex::new(None)
+});
+
+/// Picks the 'driver.*' step parameters and strips the prefix: 'driver.bootstrap_servers' -> 'bootstrap_servers'
+fn get_driver_params(step_params: HashMap<String, String>) -> HashMap<String, String> {
+    step_params
+        .into_iter()
+        .filter(|(k, _)| k.starts_with("driver."))
+        .map(|(k, v)| (
+            match k.strip_prefix("driver.") {
+                Some(k2) => String::from(k2),
+                None => k.clone()
+            }, v))
+        .collect()
+}
+
+/// Maps the record metadata to Kafka message attributes:
+/// 'kafka.topic' -> topic, 'kafka.key' -> key, 'kafka.headers.<name>' -> header '<name>'
+fn build_kafka_message(mtd: &HashMap<String, String>, payload: Vec<u8>) -> KafkaMessage {
+    let headers: HashMap<String, String> = mtd
+        .iter()
+        .filter(|(k, _)| k.starts_with("kafka.headers."))
+        .map(|(k, v)| (k.strip_prefix("kafka.headers.").unwrap().to_string(), v.clone()))
+        .collect();
+    let key = mtd.get("kafka.key").cloned();
+    let topic = mtd.get("kafka.topic").unwrap_or(&String::from("test")).clone(); // TODO: handle the missing topic
+    KafkaMessage {
+        headers,
+        key,
+        partition: None,
+        payload,
+        topic,
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn torustiq_module_get_info() -> LibInfo {
     MODULE_INFO
@@ -65,22 +124,56 @@ extern "C" fn torustiq_module_common_start(handle: ModuleHandle) -> StepStartFnR
         None => HashMap::new(),
     };
 
-    let driver_params: HashMap<String, String> = step_params
-        .into_iter()
-        .filter(|(k, _)| k.starts_with("driver."))
-        .map(|(k, v)| (
-            match k.strip_prefix("driver.") {
-                Some(k2) => String::from(k2),
-                None => k.clone()
-            }, v))
-        .collect();
-
-    *PRODUCER.lock().unwrap() = Some(KafkaProducer::new(&driver_params));
+    let topic_creation_cfg = match TopicCreationConfig::from_step_params(&step_params) {
+        Ok(c) => c,
+        Err(e) => return StepStartFnResult::ErrorMisc(string_to_cchar(e)),
+    };
+    let oauth_cfg = match OAuthConfig::from_step_params(&step_params) {
+        Ok(c) => c,
+        Err(e) => return StepStartFnResult::ErrorMisc(string_to_cchar(e)),
+    };
+    let batch_cfg = match BatchConfig::from_step_params(&step_params) {
+        Ok(c) => c,
+        Err(e) => return StepStartFnResult::ErrorMisc(string_to_cchar(e)),
+    };
+    let driver_params = match build_driver_config(&get_driver_params(step_params)) {
+        Ok(p) => p,
+        Err(e) => return StepStartFnResult::ErrorMisc(string_to_cchar(
+            format!("Invalid Kafka driver configuration in step '{}': {}", handle, e))),
+    };
+    match batch_cfg {
+        Some(c) => {
+            let batcher = Arc::new(Batcher::new(c, driver_params, topic_creation_cfg, oauth_cfg));
+            let batcher_thread = batcher.clone();
+            *FLUSH_THREAD.lock().unwrap() = Some(thread::spawn(move || thread_flush(batcher_thread)));
+            *BATCHER.lock().unwrap() = Some(batcher);
+        },
+        None => *PRODUCER.lock().unwrap() = Some(KafkaProducer::new(&driver_params, topic_creation_cfg, oauth_cfg)),
+    };
     StepStartFnResult::Ok
 }
 
 #[no_mangle]
 extern "C" fn torustiq_module_pipeline_process_record(input: Record, _h: ModuleHandle) -> ModulePipelineProcessRecordFnResult {
+    let mtd = input.get_metadata_as_hashmap();
+    let mut msg = build_kafka_message(&mtd, input.content.to_byte_vec());
+
+    if let Some(batcher) = BATCHER.lock().unwrap().clone() {
+        // The partition is only taken from metadata in batch mode, where batches are grouped by partition
+        msg.partition = match partition_from_metadata(&mtd) {
+            Ok(p) => p,
+            Err(e) => {
+                error!("Cannot send a message to Kafka: {}", e);
+                return ModulePipelineProcessRecordFnResult::Ok
+            }
+        };
+        match TopicSettings::from_metadata(&mtd) {
+            Ok(settings) => batcher.add(msg, settings),
+            Err(e) => error!("Cannot send a message to Kafka: {}", e),
+        };
+        return ModulePipelineProcessRecordFnResult::Ok
+    }
+
     let producer = match PRODUCER.lock().unwrap().clone() {
         Some(p) => p,
         None => {
@@ -92,21 +185,77 @@ extern "C" fn torustiq_module_pipeline_process_record(input: Record, _h: ModuleH
     // Instead of blocking, process a message using channel
     // Consider moving the torustiq_module_pipeline_process_record function into common module, so all modules are expected
     //   to process records in async mode (using channels)
-    let mtd = input.get_metadata_as_hashmap();
-    let headers: HashMap<String, String> = mtd
-        .iter()
-        .filter(|(k, _)| k.starts_with("kafka.headers."))
-        .map(|(k, v)| (k.strip_prefix("kafka.headers.").unwrap().to_string(), v.clone()))
-        .collect();
-    let key = mtd.get("kafka.key").cloned();
-    let topic = mtd.get("kafka.topic").unwrap_or(&String::from("test")).clone(); // TODO: handle the missing topic
-    if let Err(e) = futures::executor::block_on(producer.produce(&KafkaMessage {
-        headers,
-        key,
-        payload: input.content.to_byte_vec(),
-        topic,
-    })) {
-        print!("Failed to send a message to Kafka: {}", e)
+    if let Err(e) = futures::executor::block_on(producer.produce(&msg)) {
+        error!("Failed to send a message to Kafka: {}", e)
     }
     ModulePipelineProcessRecordFnResult::Ok
-}
\ No newline at end of file
+}
+
+/// Sends the accumulated batches, if batching is enabled, and notifies the host that the step is stopped
+#[no_mangle]
+extern "C" fn torustiq_module_common_shutdown(handle: ModuleHandle) {
+    if let Some(batcher) = BATCHER.lock().unwrap().take() {
+        batcher.stop();
+    }
+    if let Some(t) = FLUSH_THREAD.lock().unwrap().take() {
+        if t.join().is_err() {
+            error!("The thread sending Kafka batches in step '{}' has panicked", handle);
+        }
+    }
+    if let Some(args) = get_pipeline_module_configuration(handle) {
+        (args.on_step_terminate_cb)(handle);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::to_hashmap;
+
+    use super::{build_kafka_message, get_driver_params};
+
+    #[test]
+    fn test_get_driver_params() {
+        let step_params = to_hashmap(&[
+            ("driver.bootstrap_servers", "localhost:9092"),
+            ("driver.security.protocol", "plaintext"),
+            ("format", "%R"),
+            ("some.driver.param", "value"),
+        ]);
+
+        assert_eq!(get_driver_params(step_params), to_hashmap(&[
+            ("bootstrap_servers", "localhost:9092"),
+            ("security.protocol", "plaintext"),
+        ]));
+    }
+
+    #[test]
+    fn test_build_kafka_message() {
+        let mtd = to_hashmap(&[
+            ("kafka.topic", "events"),
+            ("kafka.key", "user-1"),
+            ("kafka.headers.content-type", "application/json"),
+            ("kafka.headers.trace.id", "abc"),
+            ("http.method", "POST"),
+        ]);
+        let msg = build_kafka_message(&mtd, b"payload".to_vec());
+
+        assert_eq!(msg.topic, "events");
+        assert_eq!(msg.partition, None);
+        assert_eq!(msg.key, Some(String::from("user-1")));
+        assert_eq!(msg.payload, b"payload".to_vec());
+        assert_eq!(msg.headers, to_hashmap(&[
+            ("content-type", "application/json"),
+            ("trace.id", "abc"),
+        ]));
+    }
+
+    #[test]
+    fn test_build_kafka_message_without_key_and_headers() {
+        let msg = build_kafka_message(&to_hashmap(&[("kafka.topic", "events")]), vec![]);
+
+        assert_eq!(msg.topic, "events");
+        assert_eq!(msg.partition, None);
+        assert_eq!(msg.key, None);
+        assert!(msg.headers.is_empty());
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::process::Command;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use log::debug;
+use rdkafka::client::{ClientContext, OAuthToken};
+use serde_json::Value;
+
+/// Where to get a SASL OAUTHBEARER token from
+#[derive(Clone, Debug, PartialEq)]
+pub enum OAuthTokenSource {
+    /// A shell command which prints the token to stdout
+    Command(String),
+    /// A file with the token. The file is expected to be refreshed externally
+    File(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OAuthConfig {
+    pub token_source: OAuthTokenSource,
+    /// Kafka principal name. If not set, the 'sub' claim of token is used
+    pub principal_name: Option<String>,
+}
+
+impl OAuthConfig {
+    /// Reads the OAUTHBEARER token settings from step parameters:
+    /// - oauth.token_command: a shell command which prints the token
+    /// - oauth.token_file: a file with the token
+    /// - oauth.principal_name: optional, overrides the 'sub' claim of token
+    ///
+    /// Returns None if neither command nor file is configured
+    pub fn from_step_params(params: &HashMap<String, String>) -> Result<Option<Self>, String> {
+        let token_source = match (params.get("oauth.token_command"), params.get("oauth.token_file")) {
+            (Some(_), Some(_)) => return Err(String::from("Only one of 'oauth.token_command' and 'oauth.token_file' can be set")),
+            (Some(c), None) => OAuthTokenSource::Command(c.clone()),
+            (None, Some(f)) => OAuthTokenSource::File(f.clone()),
+            (None, None) => return Ok(None),
+        };
+        Ok(Some(OAuthConfig {
+            token_source,
+            principal_name: params.get("oauth.principal_name").cloned(),
+        }))
+    }
+}
+
+/// Client context which provides SASL OAUTHBEARER tokens to librdkafka.
+/// It replaces the token refresh of librdkafka, so it's only used if 'oauth.*' step parameters are set.
+/// Otherwise clients are created with the default context, e.g. to keep 'sasl.oauthbearer.method=oidc' working
+#[derive(Clone)]
+pub struct OAuthClientContext {
+    cfg: OAuthConfig,
+}
+
+impl OAuthClientContext {
+    pub fn new(cfg: OAuthConfig) -> Self {
+        OAuthClientContext {
+            cfg,
+        }
+    }
+}
+
+impl ClientContext for OAuthClientContext {
+    const ENABLE_REFRESH_OAUTH_TOKEN: bool = true;
+
+    fn generate_oauth_token(&self, _oauthbearer_config: Option<&str>) -> Result<OAuthToken, Box<dyn Error>> {
+        let cfg = &self.cfg;
+        let token = read_token(&cfg.token_source)?;
+        let claims = parse_jwt_claims(&token)?;
+
+        let lifetime_ms = match claims.get("exp").and_then(Value::as_f64) {
+            Some(exp) => (exp * 1000.0) as i64,
+            None => return Err("The OAUTHBEARER token has no 'exp' claim".into()),
+        };
+        let principal_name = match (&cfg.principal_name, claims.get("sub").and_then(Value::as_str)) {
+            (Some(p), _) => p.clone(),
+            (None, Some(sub)) => String::from(sub),
+            (None, None) => return Err("The OAUTHBEARER token has no 'sub' claim and 'oauth.principal_name' is not set".into()),
+        };
+        debug!("Refreshed the OAUTHBEARER token for principal '{}'", principal_name);
+
+        Ok(OAuthToken {
+            token,
+            principal_name,
+            lifetime_ms,
+        })
+    }
+}
+
+fn read_token(source: &OAuthTokenSource) -> Result<String, String> {
+    let token = match source {
+        OAuthTokenSource::Command(cmd) => {
+            let output = match shell_command(cmd).output() {
+                Ok(o) => o,
+                Err(e) => return Err(format!("Failed to run the token command '{}': {}", cmd, e)),
+            };
+            if !output.status.success() {
+                return Err(format!("Token command '{}' failed with {}: {}", cmd, output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()));
+            }
+            String::from_utf8_lossy(&output.stdout).to_string()
+        },
+        OAuthTokenSource::File(path) => match fs::read_to_string(path) {
+            Ok(t) => t,
+            Err(e) => return Err(format!("Failed to read the token file '{}': {}", path, e)),
+        },
+    };
+
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(String::from("The OAUTHBEARER token is empty"));
+    }
+    Ok(String::from(token))
+}
+
+#[cfg(unix)]
+fn shell_command(cmd: &str) -> Command {
+    let mut c = Command::new("sh");
+    c.arg("-c").arg(cmd);
+    c
+}
+
+#[cfg(windows)]
+fn shell_command(cmd: &str) -> Command {
+    let mut c = Command::new("cmd");
+    c.arg("/C").arg(cmd);
+    c
+}
+
+/// Decodes the claims (i.e. payload section) of JWT. The signature is not verified
+fn parse_jwt_claims(token: &str) -> Result<serde_json::Map<String, Value>, String> {
+    let payload = match token.split('.').nth(1) {
+        Some(p) => p,
+        None => return Err(String::from("The OAUTHBEARER token is not a JWT")),
+    };
+    let payload = match URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')) {
+        Ok(p) => p,
+        Err(e) => return Err(format!("Failed to decode the payload of JWT: {}", e)),
+    };
+    match serde_json::from_slice::<Value>(&payload) {
+        Ok(Value::Object(claims)) => Ok(claims),
+        Ok(_) => Err(String::from("The payload of JWT is not a JSON object")),
+        Err(e) => Err(format!("Failed to parse the payload of JWT: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use rdkafka::client::ClientContext;
+
+    use crate::test_utils::TempDir;
+
+    use super::{OAuthClientContext, OAuthConfig, OAuthTokenSource};
+
+    const EXP: i64 = 1893456000;
+
+    fn new_jwt(claims: &str) -> String {
+        format!("{}.{}.signature", URL_SAFE_NO_PAD.encode(r#"{"alg":"RS256","typ":"JWT"}"#), URL_SAFE_NO_PAD.encode(claims))
+    }
+
+    fn new_context(token_source: OAuthTokenSource, principal_name: Option<&str>) -> OAuthClientContext {
+        OAuthClientContext::new(OAuthConfig {
+            token_source,
+            principal_name: principal_name.map(String::from),
+        })
+    }
+
+    #[test]
+    fn test_from_step_params() {
+        let params: HashMap<String, String> = HashMap::from([
+            (String::from("oauth.token_file"), String::from("/var/run/token")),
+            (String::from("oauth.principal_name"), String::from("svc")),
+        ]);
+        assert_eq!(OAuthConfig::from_step_params(&params), Ok(Some(OAuthConfig {
+            token_source: OAuthTokenSource::File(String::from("/var/run/token")),
+            principal_name: Some(String::from("svc")),
+        })));
+        assert_eq!(OAuthConfig::from_step_params(&HashMap::new()), Ok(None));
+        assert!(OAuthConfig::from_step_params(&HashMap::from([
+            (String::from("oauth.token_file"), String::from("/var/run/token")),
+            (String::from("oauth.token_command"), String::from("get-token")),
+        ])).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_generate_oauth_token_from_command() {
+        let jwt = new_jwt(&format!(r#"{{"sub":"torustiq","exp":{}}}"#, EXP));
+        let dir = TempDir::new("oauth_command");
+        let script_path = dir.join("token.sh");
+        fs::write(&script_path, format!("#!/bin/sh\necho '{}'\n", jwt)).unwrap();
+
+        let ctx = new_context(OAuthTokenSource::Command(format!("sh {}", script_path.to_str().unwrap())), None);
+        let token = ctx.generate_oauth_token(None).expect("Failed to generate a token");
+
+        assert_eq!(token.token, jwt);
+        assert_eq!(token.principal_name, "torustiq");
+        assert_eq!(token.lifetime_ms, EXP * 1000);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_generate_oauth_token_command_failure() {
+        let ctx = new_context(OAuthTokenSource::Command(String::from("echo 'no token' >&2; exit 3")), None);
+        let err = ctx.generate_oauth_token(None).err().expect("Token generation must fail").to_string();
+        assert!(err.contains("no token"), "Unexpected error: {}", err);
+    }
+
+    #[test]
+    fn test_generate_oauth_token_from_file() {
+        let jwt = new_jwt(&format!(r#"{{"exp":{}}}"#, EXP));
+        let dir = TempDir::new("oauth_file");
+        let token_path = dir.join("token.jwt");
+        fs::write(&token_path, format!("{}\n", jwt)).unwrap();
+
+        let ctx = new_context(OAuthTokenSource::File(token_path.to_str().unwrap().to_string()), Some("svc"));
+        let token = ctx.generate_oauth_token(None).expect("Failed to generate a token");
+
+        assert_eq!(token.token, jwt);
+        assert_eq!(token.principal_name, "svc");
+        assert_eq!(token.lifetime_ms, EXP * 1000);
+    }
+
+    #[test]
+    fn test_generate_oauth_token_invalid() {
+        let dir = TempDir::new("oauth_invalid");
+        let token_path = dir.join("token.jwt");
+        fs::write(&token_path, new_jwt(r#"{"sub":"torustiq"}"#)).unwrap();
+        let ctx = new_context(OAuthTokenSource::File(token_path.to_str().unwrap().to_string()), None);
+        assert!(ctx.generate_oauth_token(None).is_err()); // no 'exp' claim
+
+        fs::write(&token_path, "opaque-token").unwrap();
+        assert!(ctx.generate_oauth_token(None).is_err());
+    }
+}
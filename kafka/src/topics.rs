@@ -0,0 +1,301 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use futures::channel::oneshot;
+use log::info;
+use rdkafka::admin::{AdminClient, AdminOptions, NewTopic, TopicReplication};
+use rdkafka::client::DefaultClientContext;
+use rdkafka::config::ClientConfig;
+use rdkafka::types::RDKafkaErrorCode;
+
+use crate::oauth::OAuthClientContext;
+
+/// How long to wait for the topic metadata before trying to create a topic
+const METADATA_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Settings of topics which are created automatically.
+/// Partitions and replication factor set to -1 mean the broker defaults
+#[derive(Clone, Debug, PartialEq)]
+pub struct TopicCreationConfig {
+    pub num_partitions: i32,
+    pub replication_factor: i32,
+    pub configs: HashMap<String, String>,
+}
+
+impl TopicCreationConfig {
+    /// Reads the topic creation settings from step parameters:
+    /// - auto_create_topics: 'true' or 'false' (default)
+    /// - topics.num_partitions
+    /// - topics.replication_factor
+    /// - topics.config.<name>: topic-level configs, e.g. 'topics.config.retention.ms'
+    ///
+    /// Returns None if automatic creation of topics is disabled
+    pub fn from_step_params(params: &HashMap<String, String>) -> Result<Option<Self>, String> {
+        match params.get("auto_create_topics").map(String::as_str) {
+            None | Some("false") => return Ok(None),
+            Some("true") => {},
+            Some(v) => return Err(format!("Invalid value of 'auto_create_topics': '{}'. Expected 'true' or 'false'", v)),
+        };
+
+        let parse_i32 = |k: &str| -> Result<i32, String> {
+            match params.get(k) {
+                Some(v) => v.parse::<i32>().map_err(|e| format!("Invalid value of '{}': '{}': {}", k, v, e)),
+                None => Ok(-1),
+            }
+        };
+        let configs: HashMap<String, String> = params
+            .iter()
+            .filter_map(|(k, v)| k.strip_prefix("topics.config.").map(|k2| (String::from(k2), v.clone())))
+            .collect();
+
+        Ok(Some(TopicCreationConfig {
+            num_partitions: parse_i32("topics.num_partitions")?,
+            replication_factor: parse_i32("topics.replication_factor")?,
+            configs,
+        }))
+    }
+
+    /// Builds the CreateTopics request of a topic
+    pub fn new_topic<'a>(&'a self, topic: &'a str) -> NewTopic<'a> {
+        let mut new_topic = NewTopic::new(topic, self.num_partitions,
+            TopicReplication::Fixed(self.replication_factor));
+        for (k, v) in &self.configs {
+            new_topic = new_topic.set(k, v);
+        }
+        new_topic
+    }
+}
+
+/// The admin client is created with the same context as producer
+enum RdAdminClient {
+    Default(AdminClient<DefaultClientContext>),
+    OAuth(AdminClient<OAuthClientContext>),
+}
+
+/// Creates missing topics and remembers the topics which are known to exist
+#[derive(Clone)]
+pub struct TopicAdmin {
+    admin_client: Arc<RdAdminClient>,
+    cfg: TopicCreationConfig,
+    known_topics: Arc<Mutex<HashSet<String>>>,
+}
+
+impl TopicAdmin {
+    /// Creates an admin client. The OAuth context is used only if tokens are provided by the module
+    pub fn new(kafka_config: &ClientConfig, context: Option<OAuthClientContext>, cfg: TopicCreationConfig) -> Self {
+        // Metadata requests of admin client must not create topics with broker defaults
+        let mut admin_config = kafka_config.clone();
+        admin_config.set("allow.auto.create.topics", "false");
+        let admin_client = match context {
+            Some(c) => RdAdminClient::OAuth(admin_config.create_with_context(c).expect("Can't create a Kafka admin client")),
+            None => RdAdminClient::Default(admin_config.create().expect("Can't create a Kafka admin client")),
+        };
+        TopicAdmin {
+            admin_client: Arc::new(admin_client),
+            cfg,
+            known_topics: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// Creates a topic unless it exists already. Existing topics are left untouched
+    pub async fn ensure_topic(&self, topic: &str) -> Result<(), String> {
+        if self.known_topics.lock().unwrap().contains(topic) {
+            return Ok(())
+        }
+        if self.topic_exists(topic).await? {
+            self.known_topics.lock().unwrap().insert(String::from(topic));
+            return Ok(())
+        }
+
+        let new_topic = self.cfg.new_topic(topic);
+        let opts = AdminOptions::new();
+        let results = match self.admin_client.as_ref() {
+            RdAdminClient::Default(c) => c.create_topics(&[new_topic], &opts).await,
+            RdAdminClient::OAuth(c) => c.create_topics(&[new_topic], &opts).await,
+        };
+        let results = match results {
+            Ok(r) => r,
+            Err(e) => return Err(format!("Failed to create a Kafka topic '{}': {:?}", topic, e)),
+        };
+        for res in results {
+            match res {
+                Ok(t) => info!("Created a Kafka topic '{}'", t),
+                Err((_, RDKafkaErrorCode::TopicAlreadyExists)) => {},
+                Err((t, e)) => return Err(format!("Failed to create a Kafka topic '{}': {}", t, e)),
+            }
+        }
+
+        self.known_topics.lock().unwrap().insert(String::from(topic));
+        Ok(())
+    }
+
+    /// Checks the topic metadata, so no CreateTopics request is sent for existing topics.
+    /// The metadata request blocks, so it's sent from a separate thread to keep the executor free
+    async fn topic_exists(&self, topic: &str) -> Result<bool, String> {
+        let admin_client = self.admin_client.clone();
+        let topic_name = String::from(topic);
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            let _ = tx.send(fetch_topic_exists(&admin_client, &topic_name));
+        });
+        match rx.await {
+            Ok(r) => r,
+            Err(_) => Err(format!("Failed to fetch the metadata of Kafka topic '{}': the request was cancelled", topic)),
+        }
+    }
+}
+
+fn fetch_topic_exists(admin_client: &RdAdminClient, topic: &str) -> Result<bool, String> {
+    let metadata = match admin_client {
+        RdAdminClient::Default(c) => c.inner().fetch_metadata(Some(topic), METADATA_TIMEOUT),
+        RdAdminClient::OAuth(c) => c.inner().fetch_metadata(Some(topic), METADATA_TIMEOUT),
+    };
+    match metadata {
+        Ok(m) => Ok(m.topics().iter().any(|t| t.name() == topic && t.error().is_none())),
+        Err(e) => Err(format!("Failed to fetch the metadata of Kafka topic '{}': {}", topic, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    use rdkafka::admin::TopicReplication;
+    use rdkafka::config::ClientConfig;
+    use rdkafka::mocking::MockCluster;
+    use rdkafka::producer::{BaseProducer, DefaultProducerContext, Producer};
+
+    use crate::test_utils::to_hashmap;
+
+    use super::{TopicAdmin, TopicCreationConfig};
+
+    #[test]
+    fn test_from_step_params_disabled() {
+        assert_eq!(TopicCreationConfig::from_step_params(&to_hashmap(&[])), Ok(None));
+        assert_eq!(TopicCreationConfig::from_step_params(&to_hashmap(&[
+            ("auto_create_topics", "false"),
+            ("topics.num_partitions", "6"),
+        ])), Ok(None));
+    }
+
+    #[test]
+    fn test_from_step_params_defaults() {
+        assert_eq!(TopicCreationConfig::from_step_params(&to_hashmap(&[("auto_create_topics", "true")])),
+            Ok(Some(TopicCreationConfig {
+                num_partitions: -1,
+                replication_factor: -1,
+                configs: HashMap::new(),
+            })));
+    }
+
+    #[test]
+    fn test_from_step_params() {
+        let params = to_hashmap(&[
+            ("auto_create_topics", "true"),
+            ("topics.num_partitions", "6"),
+            ("topics.replication_factor", "3"),
+            ("topics.config.retention.ms", "86400000"),
+            ("topics.config.cleanup.policy", "compact"),
+            ("driver.bootstrap_servers", "localhost:9092"),
+        ]);
+        assert_eq!(TopicCreationConfig::from_step_params(&params), Ok(Some(TopicCreationConfig {
+            num_partitions: 6,
+            replication_factor: 3,
+            configs: to_hashmap(&[
+                ("retention.ms", "86400000"),
+                ("cleanup.policy", "compact"),
+            ]),
+        })));
+    }
+
+    #[test]
+    fn test_from_step_params_invalid() {
+        assert!(TopicCreationConfig::from_step_params(&to_hashmap(&[("auto_create_topics", "yes")])).is_err());
+        assert!(TopicCreationConfig::from_step_params(&to_hashmap(&[
+            ("auto_create_topics", "true"),
+            ("topics.num_partitions", "many"),
+        ])).is_err());
+    }
+
+    #[test]
+    fn test_new_topic() {
+        let cfg = TopicCreationConfig::from_step_params(&to_hashmap(&[
+            ("auto_create_topics", "true"),
+            ("topics.num_partitions", "6"),
+            ("topics.replication_factor", "3"),
+            ("topics.config.retention.ms", "86400000"),
+            ("topics.config.cleanup.policy", "compact"),
+        ])).unwrap().unwrap();
+        let new_topic = cfg.new_topic("events");
+
+        assert_eq!(new_topic.name, "events");
+        assert_eq!(new_topic.num_partitions, 6);
+        assert!(matches!(new_topic.replication, TopicReplication::Fixed(3)));
+        let mut configs = new_topic.config.clone();
+        configs.sort();
+        assert_eq!(configs, vec![("cleanup.policy", "compact"), ("retention.ms", "86400000")]);
+
+        // Partitions and replication factor are left to the broker
+        let cfg = TopicCreationConfig::from_step_params(&to_hashmap(&[("auto_create_topics", "true")])).unwrap().unwrap();
+        let new_topic = cfg.new_topic("events");
+        assert_eq!(new_topic.num_partitions, -1);
+        assert!(matches!(new_topic.replication, TopicReplication::Fixed(-1)));
+        assert!(new_topic.config.is_empty());
+    }
+
+    #[test]
+    fn test_ensure_topic_cached() {
+        // No broker is listening there, so only cached topics can be ensured
+        let mut kafka_config = ClientConfig::new();
+        kafka_config
+            .set("bootstrap.servers", "127.0.0.1:1")
+            .set("socket.timeout.ms", "1000");
+        let admin = TopicAdmin::new(&kafka_config, None, TopicCreationConfig {
+            num_partitions: 1,
+            replication_factor: 1,
+            configs: HashMap::new(),
+        });
+        admin.known_topics.lock().unwrap().insert(String::from("cached"));
+
+        let started = Instant::now();
+        futures::executor::block_on(admin.ensure_topic("cached")).expect("Failed to ensure a cached topic");
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    fn num_partitions(kafka_config: &ClientConfig, topic: &str) -> usize {
+        let producer: BaseProducer = kafka_config.create().expect("Can't create a Kafka producer");
+        let metadata = producer.client().fetch_metadata(Some(topic), Duration::from_secs(5))
+            .expect("Can't fetch the topic metadata");
+        metadata.topics().iter().find(|t| t.name() == topic).map(|t| t.partitions().len()).unwrap_or(0)
+    }
+
+    #[test]
+    fn test_ensure_topic() {
+        let mock_cluster: MockCluster<'static, DefaultProducerContext> = MockCluster::new(1)
+            .expect("Can't create a mock Kafka cluster");
+        mock_cluster.create_topic("existing", 2, 1).expect("Can't create a test topic");
+        let mut kafka_config = ClientConfig::new();
+        kafka_config
+            .set("bootstrap.servers", mock_cluster.bootstrap_servers())
+            .set("socket.timeout.ms", "2000");
+        let admin = TopicAdmin::new(&kafka_config, None, TopicCreationConfig {
+            num_partitions: 3,
+            replication_factor: 1,
+            configs: HashMap::new(),
+        });
+
+        // An existing topic is left untouched
+        futures::executor::block_on(admin.ensure_topic("existing")).expect("Failed to check an existing topic");
+        assert_eq!(num_partitions(&kafka_config, "existing"), 2);
+        assert!(admin.known_topics.lock().unwrap().contains("existing"));
+
+        // A missing topic is created. The mock cluster doesn't implement CreateTopics,
+        // so the request is expected to reach the admin API and fail there
+        let err = futures::executor::block_on(admin.ensure_topic("missing")).unwrap_err();
+        assert!(err.contains("Failed to create a Kafka topic 'missing'"), "Unexpected error: {}", err);
+        assert!(!admin.known_topics.lock().unwrap().contains("missing"));
+    }
+}
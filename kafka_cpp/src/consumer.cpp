@@ -0,0 +1,151 @@
+#include "consumer.hpp"
+
+namespace torustiq_kafka_cpp
+{
+
+// Timeout of a single consume call in the consume thread
+const int CONSUME_TIMEOUT_MS = 100;
+
+Consumer::Consumer(torustiq_common::ModulePipelineConfigureArgs args, map<string, string> driver_params, vector<string> topics)
+{
+    this->args = args;
+    this->driver_params = driver_params;
+    this->topics = topics;
+}
+
+Consumer::~Consumer()
+{
+    this->stop();
+}
+
+optional<string> Consumer::start()
+{
+    string errstr;
+
+    // Set Kafka properties from driver parameter section
+    RdKafka::Conf *conf = RdKafka::Conf::create(RdKafka::Conf::CONF_GLOBAL);
+    map<string, string>::iterator it;
+    for(it = this->driver_params.begin(); it != this->driver_params.end(); it++)
+    {
+        string k = torustiq_kafka_cpp::utils::strings::replace_all(it->first, "_", ".");
+        string v = it->second;
+        if (conf->set(k.c_str(), v.c_str(), errstr) != RdKafka::Conf::CONF_OK) {
+            delete conf;
+            return string(errstr);
+        }
+    }
+
+    this->rd_consumer = RdKafka::KafkaConsumer::create(conf, errstr);
+    delete conf;
+    if (!this->rd_consumer) {
+        return string(errstr);
+    }
+
+    RdKafka::ErrorCode err = this->rd_consumer->subscribe(this->topics);
+    if (err != RdKafka::ERR_NO_ERROR) {
+        delete this->rd_consumer;
+        this->rd_consumer = nullptr;
+        return string("Failed to subscribe to topics: ") + RdKafka::err2str(err);
+    }
+
+    this->running = true;
+    this->rd_consumer_thread = thread(kafka_consume_thread, this);
+    return nullopt;
+}
+
+void Consumer::stop()
+{
+    if (!this->running)
+    {
+        return;
+    }
+    this->running = false;
+    this->rd_consumer_thread.join();
+
+    this->rd_consumer->close();
+    delete this->rd_consumer;
+    this->rd_consumer = nullptr;
+}
+
+bool Consumer::is_running()
+{
+    return this->running;
+}
+
+void Consumer::consume()
+{
+    RdKafka::Message *message = this->rd_consumer->consume(CONSUME_TIMEOUT_MS);
+    switch (message->err())
+    {
+        case RdKafka::ERR_NO_ERROR:
+            this->args.on_data_receive_cb(message_to_record(message), this->args.module_handle);
+            break;
+        case RdKafka::ERR__TIMED_OUT:
+        case RdKafka::ERR__PARTITION_EOF:
+            break;
+        default:
+            cerr << "Failed to consume a message in step '" << this->args.module_handle << "': "
+                << message->errstr() << endl;
+    }
+    delete message;
+}
+
+static torustiq_common::RecordMetadata new_metadata(const string &name, const string &value)
+{
+    return {
+        .name = utils::strings::to_cchar(name),
+        .value = utils::strings::to_cchar(value),
+    };
+}
+
+torustiq_common::Record message_to_record(RdKafka::Message *message)
+{
+    vector<torustiq_common::RecordMetadata> metadata;
+    metadata.push_back(new_metadata("kafka.topic", message->topic_name()));
+    metadata.push_back(new_metadata("kafka.partition", to_string(message->partition())));
+    metadata.push_back(new_metadata("kafka.offset", to_string(message->offset())));
+    if (nullptr != message->key_pointer())
+    {
+        metadata.push_back(new_metadata("kafka.key",
+            string(static_cast<const char *>(message->key_pointer()), message->key_len())));
+    }
+    RdKafka::Headers *headers = message->headers();
+    if (nullptr != headers)
+    {
+        for (RdKafka::Headers::Header header: headers->get_all())
+        {
+            string value = nullptr == header.value()
+                ? string()
+                : string(static_cast<const char *>(header.value()), header.value_size());
+            metadata.push_back(new_metadata(string("kafka.headers.") + header.key(), value));
+        }
+    }
+
+    torustiq_common::RecordMetadata *metadata_data = new torustiq_common::RecordMetadata[metadata.size()];
+    copy(metadata.begin(), metadata.end(), metadata_data);
+
+    uint8_t *content = new uint8_t[message->len()];
+    if (message->len() > 0)
+    {
+        memcpy(content, message->payload(), message->len());
+    }
+
+    return {
+        .content = {
+            .bytes = content,
+            .len = message->len(),
+        },
+        .metadata = {
+            .data = metadata_data,
+            .len = static_cast<torustiq_common::Uint>(metadata.size()),
+        },
+    };
+}
+
+void kafka_consume_thread(Consumer *consumer)
+{
+    while(consumer->is_running()) {
+        consumer->consume();
+    }
+}
+}
@@ -0,0 +1,58 @@
+#ifndef _TORUSTIQ_KAFKA_CPP_CONSUMER_H_
+#define _TORUSTIQ_KAFKA_CPP_CONSUMER_H_
+
+#include <algorithm>
+#include <atomic>
+#include <cstring>
+#include <iostream>
+#include <map>
+#include <optional>
+#include <string>
+#include <thread>
+#include <vector>
+
+#include <librdkafka/rdkafkacpp.h>
+
+#include "utils/strings.hpp"
+#include "../../torustiq_common_typedefs.hpp"
+
+using namespace std;
+
+namespace torustiq_kafka_cpp
+{
+class Consumer {
+    public:
+        Consumer(torustiq_common::ModulePipelineConfigureArgs args, map<string, string> driver_params, vector<string> topics);
+        // Stops the consumer if it's running
+        ~Consumer();
+
+        optional<string> start();
+        // Stops the consume thread and leaves the consumer group
+        void stop();
+        bool is_running();
+        // Waits for a message and passes it to the host
+        void consume();
+
+    private:
+        torustiq_common::ModulePipelineConfigureArgs args;
+        map<string, string> driver_params;
+        vector<string> topics;
+
+        atomic<bool> running{false};
+        RdKafka::KafkaConsumer *rd_consumer = nullptr;
+        thread rd_consumer_thread;
+};
+
+// Converts a Kafka message into a record. Metadata:
+// - kafka.topic
+// - kafka.partition
+// - kafka.offset
+// - kafka.key (if the message has a key)
+// - kafka.headers.<name> for each header
+// Memory is released by torustiq_module_pipeline_free_record
+torustiq_common::Record message_to_record(RdKafka::Message *message);
+
+void kafka_consume_thread(Consumer *consumer);
+}
+
+#endif
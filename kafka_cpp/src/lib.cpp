@@ -5,14 +5,19 @@ const char *MODULE_NAME = "C++ implementation of Kafka";
 const LibInfo MODULE_INFO = {
     .api_version = CURRENT_API_VERSION,
     .id = MODULE_ID,
-    .kind = ModuleKind::Pipeline,
+    .kind = ModuleKind::Step,
     .name = MODULE_NAME,
 };
 
+// Guards ARGS, STEP_PARAMS, PRODUCERS and CONSUMERS: steps are started and served in different threads
+mutex STATE_MTX;
 map<ModuleHandle, ModulePipelineConfigureArgs> ARGS;
 map<ModuleHandle, map<string, string>> STEP_PARAMS;
-
-Producer *PRODUCER = nullptr;
+// A producer is created per step. A record being processed holds a reference,
+// so a producer is destroyed once the step is shut down and all its records are processed
+map<ModuleHandle, shared_ptr<Producer>> PRODUCERS;
+// A consumer is created per source step
+map<ModuleHandle, shared_ptr<Consumer>> CONSUMERS;
 
 extern "C" LibInfo torustiq_module_get_info()
 {
@@ -26,15 +31,14 @@ extern "C" void torustiq_module_init()
 
 extern "C" ModulePipelineConfigureFnResult torustiq_module_pipeline_configure(ModulePipelineConfigureArgs args)
 {
-    ModulePipelineConfigureFnResult result;
-    
-    if (args.kind != PipelineModuleKind::Destination)
+    if (args.kind != PipelineModuleKind::Source && args.kind != PipelineModuleKind::Destination)
     {
         return {
             .tag = ModulePipelineConfigureFnResult::Tag::ErrorKindNotSupported,
         };
     }
 
+    lock_guard<mutex> lock(STATE_MTX);
     ARGS[args.module_handle] = args;
 
     return {
@@ -44,6 +48,7 @@ extern "C" ModulePipelineConfigureFnResult torustiq_module_pipeline_configure(Mo
 
 extern "C" void torustiq_module_common_set_param(ModuleHandle h, ConstCharPtr k, ConstCharPtr v)
 {
+    lock_guard<mutex> lock(STATE_MTX);
     if (!maps::key_exists(h, STEP_PARAMS))
     {
         STEP_PARAMS[h] = {};
@@ -54,33 +59,97 @@ extern "C" void torustiq_module_common_set_param(ModuleHandle h, ConstCharPtr k,
 
 extern "C" void torustiq_module_common_shutdown(ModuleHandle h)
 {
-    if (!maps::key_exists(h, ARGS))
+    ModulePipelineConfigureArgs args;
+    shared_ptr<Producer> producer;
+    shared_ptr<Consumer> consumer;
     {
-        return;
+        lock_guard<mutex> lock(STATE_MTX);
+        if (!maps::key_exists(h, ARGS))
+        {
+            return;
+        }
+        args = ARGS[h];
+        if (maps::key_exists(h, PRODUCERS))
+        {
+            producer = PRODUCERS[h];
+            PRODUCERS.erase(h);
+        }
+        if (maps::key_exists(h, CONSUMERS))
+        {
+            consumer = CONSUMERS[h];
+            CONSUMERS.erase(h);
+        }
     }
-    ARGS[h].on_step_terminate_cb(h);
+
+    // Stops consuming and leaves the consumer group
+    consumer.reset();
+
+    // Stops the producer unless some record is still being processed.
+    // Otherwise the producer is stopped once the processing is over
+    producer.reset();
+    args.on_step_terminate_cb(h);
 }
 
+// Frees strings returned by the module to the host. All of them are allocated with strings::to_cchar
 extern "C" void torustiq_module_common_free_char(const char* c) {
-    delete c;
+    delete[] c;
 }
 
+// Frees records created by the module, see message_to_record
 extern "C" void torustiq_module_pipeline_free_record(Record r) {
-    delete r.content.bytes;
-    delete r.metadata.data;
+    delete[] r.content.bytes;
+    for (Uint i = 0; i < r.metadata.len; i++)
+    {
+        delete[] r.metadata.data[i].name;
+        delete[] r.metadata.data[i].value;
+    }
+    delete[] r.metadata.data;
+}
+
+// Starts a source step. Topics are set in 'topics' step param as a comma-separated list
+StepStartFnResult start_consumer(ModulePipelineConfigureArgs args, map<string, string> step_params,
+    map<string, string> driver_params)
+{
+    ModuleHandle h = args.module_handle;
+    if (!maps::key_exists("topics", step_params))
+    {
+        return {
+            .tag = StepStartFnResult::Tag::ErrorMisc,
+            .error_misc = {
+                ._0 = strings::to_cchar(string("Missing the 'topics' param in step '") + to_string(h) + string("'")),
+            },
+        };
+    }
+
+    vector<string> topics = strings::split(step_params["topics"], ',');
+    shared_ptr<Consumer> consumer = make_shared<Consumer>(args, driver_params, topics);
+    optional<string> error = consumer->start();
+    if (nullopt != error)
+    {
+        return {
+            .tag = StepStartFnResult::Tag::ErrorMisc,
+            .error_misc = {
+                ._0 = strings::to_cchar(error.value()),
+            },
+        };
+    }
+
+    lock_guard<mutex> lock(STATE_MTX);
+    CONSUMERS[h] = consumer;
+    return {
+        .tag = StepStartFnResult::Tag::Ok,
+    };
 }
 
 extern "C" StepStartFnResult torustiq_module_common_start(ModuleHandle h)
 {
+    unique_lock<mutex> lock(STATE_MTX);
     if (!maps::key_exists(h, ARGS))
     {
         return {
             .tag = StepStartFnResult::Tag::ErrorMisc,
             .error_misc = {
-                // TODO:
-                // Free result here and below
-                // Is free result needed in Rust code too?
-                ._0 = (new string(string("Init args for step '") + to_string(h) + string("' not found")))->c_str(),
+                ._0 = strings::to_cchar(string("Init args for step '") + to_string(h) + string("' not found")),
             },
         };
     }
@@ -89,13 +158,14 @@ extern "C" StepStartFnResult torustiq_module_common_start(ModuleHandle h)
         return {
             .tag = StepStartFnResult::Tag::ErrorMisc,
             .error_misc = {
-                ._0 = (new string(string("Step params for step '") + to_string(h) + string("' not found")))->c_str(),
+                ._0 = strings::to_cchar(string("Step params for step '") + to_string(h) + string("' not found")),
             },
         };
     }
 
     ModulePipelineConfigureArgs args = ARGS[h];
     map<string, string> step_params = STEP_PARAMS[h];
+    lock.unlock();
     map<string, string> driver_params;
 
     map<string, string>::iterator it;
@@ -112,25 +182,28 @@ extern "C" StepStartFnResult torustiq_module_common_start(ModuleHandle h)
         driver_params[k] = v;
     }
 
-    PRODUCER = new Producer(driver_params);
-    optional<string> error = PRODUCER->start();
-    StepStartFnResult res;
-    if (nullopt == error)
+    if (args.kind == PipelineModuleKind::Source)
     {
-        res  = {
-            .tag = StepStartFnResult::Tag::Ok,
-        };
-    } else {
-        string *err = new string(error.value());
-        res = {
+        return start_consumer(args, step_params, driver_params);
+    }
+
+    shared_ptr<Producer> producer = make_shared<Producer>(driver_params);
+    optional<string> error = producer->start();
+    if (nullopt != error)
+    {
+        return {
             .tag = StepStartFnResult::Tag::ErrorMisc,
             .error_misc = {
-                ._0 = err->c_str(),
+                ._0 = strings::to_cchar(error.value()),
             },
         };
     }
 
-    return res;
+    lock.lock();
+    PRODUCERS[h] = producer;
+    return {
+        .tag = StepStartFnResult::Tag::Ok,
+    };
 }
 
 extern "C" ModulePipelineProcessRecordFnResult torustiq_module_pipeline_process_record(Record in, ModuleHandle h)
@@ -165,17 +238,33 @@ extern "C" ModulePipelineProcessRecordFnResult torustiq_module_pipeline_process_
     {
         return {
             .tag = ModulePipelineProcessRecordFnResult::Tag::Err,
-            .err = (new string("Missing the topic name in metadata"))->c_str(),
+            .err = strings::to_cchar("Missing the topic name in metadata"),
         };
     }
     string topic = metadata["kafka.topic"];
 
-    optional<string> err = PRODUCER->produce(topic, &key, &headers, &in.content);
+    shared_ptr<Producer> producer;
+    {
+        lock_guard<mutex> lock(STATE_MTX);
+        if (maps::key_exists(h, PRODUCERS))
+        {
+            producer = PRODUCERS[h];
+        }
+    }
+    if (nullptr == producer)
+    {
+        return {
+            .tag = ModulePipelineProcessRecordFnResult::Tag::Err,
+            .err = strings::to_cchar(string("Producer for step '") + to_string(h) + string("' is not running")),
+        };
+    }
+
+    optional<string> err = producer->produce(h, topic, &key, &headers, &in.content);
     if (err.has_value())
     {
         return {
             .tag = ModulePipelineProcessRecordFnResult::Tag::Err,
-            .err = (new string(err.value()))->c_str(),
+            .err = strings::to_cchar(err.value()),
         };
     }
 
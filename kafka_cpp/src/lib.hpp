@@ -3,12 +3,16 @@
 
 #include <cstring>
 #include <map>
+#include <memory>
+#include <mutex>
 #include <optional>
 #include <string>
+#include <vector>
 
 #include "../../torustiq_common_typedefs.hpp"
 #include "utils/maps.hpp"
 #include "utils/strings.hpp"
+#include "consumer.hpp"
 #include "producer.hpp"
 
 using namespace std;
@@ -5,11 +5,40 @@
 namespace torustiq_kafka_cpp
 {
 
+// Timeout of a single poll call in the poll thread
+const int POLL_TIMEOUT_MS = 100;
+// How long to wait for delivery of pending messages on producer stop
+const int FLUSH_TIMEOUT_MS = 10000;
+
+// Called from the poll thread once the broker acknowledged or rejected the message, or the message timed out
+void DeliveryReportCb::dr_cb(RdKafka::Message &message)
+{
+    DeliveryReport *report = static_cast<DeliveryReport *>(message.msg_opaque());
+    if (nullptr == report)
+    {
+        return;
+    }
+
+    if (message.err() != RdKafka::ERR_NO_ERROR)
+    {
+        report->result.set_value(string("Failed to deliver message #") + to_string(report->message_seq)
+            + string(" of step '") + to_string(report->step) + string("' to topic '") + report->topic
+            + string("': ") + message.errstr());
+        return;
+    }
+    report->result.set_value(nullopt);
+}
+
 Producer::Producer(map<string, string> driver_params)
 {
     this->driver_params = driver_params;
 }
 
+Producer::~Producer()
+{
+    this->stop();
+}
+
 optional<string> Producer::start()
 {
     string errstr;
@@ -22,22 +51,54 @@ optional<string> Producer::start()
         string k = torustiq_kafka_cpp::utils::strings::replace_all(it->first, "_", ".");
         string v = it->second;
         if (conf->set(k.c_str(), v.c_str(), errstr) != RdKafka::Conf::CONF_OK) {
+            delete conf;
             return string(errstr);
         }
     }
 
-    this->rd_producer = RdKafka::Producer::create(conf, errstr);
-    if (!this->rd_producer) {
+    if (conf->set("dr_cb", &this->delivery_report_cb, errstr) != RdKafka::Conf::CONF_OK) {
+        delete conf;
         return string(errstr);
     }
 
+    this->rd_producer = RdKafka::Producer::create(conf, errstr);
     delete conf;
+    if (!this->rd_producer) {
+        return string(errstr);
+    }
 
+    this->running = true;
     this->rd_producer_poll_thread = thread(kafka_poll_thread, this);
     return nullopt;
 }
 
-optional<string> Producer::produce(const string topic, const optional<string> *key, const map<string, string> *headers, const torustiq_common::ByteBuffer *buffer)
+void Producer::stop()
+{
+    if (!this->running)
+    {
+        return;
+    }
+    this->running = false;
+    this->rd_producer_poll_thread.join();
+
+    // Serves the remaining delivery reports. Messages which are not delivered in time are purged,
+    // so their reports are served as failures and no produce() call keeps waiting
+    if (this->rd_producer->flush(FLUSH_TIMEOUT_MS) != RdKafka::ERR_NO_ERROR)
+    {
+        this->rd_producer->purge(RdKafka::Producer::PURGE_QUEUE | RdKafka::Producer::PURGE_INFLIGHT);
+        this->rd_producer->poll(0);
+    }
+    delete this->rd_producer;
+    this->rd_producer = nullptr;
+}
+
+bool Producer::is_running()
+{
+    return this->running;
+}
+
+optional<string> Producer::produce(torustiq_common::ModuleHandle step, const string topic, const optional<string> *key,
+    const map<string, string> *headers, const torustiq_common::ByteBuffer *buffer)
 {
     RdKafka::Headers *rd_headers = RdKafka::Headers::create();
     for (pair<const string, string> item: *headers)
@@ -45,17 +106,27 @@ optional<string> Producer::produce(const string topic, const optional<string> *k
         rd_headers->add(item.first, item.second);
     }
 
+    DeliveryReport report;
+    report.step = step;
+    report.message_seq = this->next_message_seq++;
+    report.topic = topic;
+    future<optional<string>> delivery_result = report.result.get_future();
+
     this->rd_producer_lock();
     RdKafka::ErrorCode err = this->rd_producer->produce(topic, RdKafka::Topic::PARTITION_UA,
-        RdKafka::Producer::RK_MSG_COPY, buffer->bytes, buffer->len, key, 0,
-        0, rd_headers, NULL);
+        RdKafka::Producer::RK_MSG_COPY, buffer->bytes, buffer->len,
+        key->has_value() ? key->value().c_str() : NULL, key->has_value() ? key->value().length() : 0,
+        0, rd_headers, &report);
     this->rd_producer_unlock();
 
     if (err != RdKafka::ERR_NO_ERROR) {
+        // Headers are owned by librdkafka only if the message was enqueued
+        delete rd_headers;
         return RdKafka::err2str(err);
     }
 
-    return nullopt;
+    // The delivery report is guaranteed to arrive within 'message.timeout.ms'
+    return delivery_result.get();
 }
 
 void Producer::rd_producer_lock()
@@ -70,16 +141,15 @@ void Producer::rd_producer_unlock()
 
 void Producer::rd_producer_poll()
 {
-    this->rd_producer->poll(0);
+    this->rd_producer->poll(POLL_TIMEOUT_MS);
 }
 
+// Serves delivery reports. Polling doesn't take the producer lock: librdkafka handles are thread-safe,
+// and produce() waits for a delivery report which is served by this thread
 void kafka_poll_thread(Producer *producer)
 {
-    while(true) {
-        producer->rd_producer_lock();
+    while(producer->is_running()) {
         producer->rd_producer_poll();
-        producer->rd_producer_unlock();
-        this_thread::sleep_for(std::chrono::milliseconds(100));
     }
 }
 }
\ No newline at end of file
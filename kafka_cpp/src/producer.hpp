@@ -2,6 +2,8 @@
 #define _TORUSTIQ_KAFKA_CPP_PRODUCER_H_
 
 #include <algorithm>
+#include <atomic>
+#include <future>
 #include <iostream>
 #include <map>
 #include <mutex>
@@ -18,14 +20,36 @@ using namespace std;
 
 namespace torustiq_kafka_cpp
 {
+// Attached to each produced message as opaque. Maps the delivery report back to the step and record.
+// Lives on the stack of Producer::produce, which waits for the report
+struct DeliveryReport {
+    torustiq_common::ModuleHandle step;
+    // Sequence number of the message within the producer. Only used to tell messages apart in errors
+    uint64_t message_seq;
+    string topic;
+    // Empty on success, an error message on failure
+    promise<optional<string>> result;
+};
+
+class DeliveryReportCb : public RdKafka::DeliveryReportCb {
+    public:
+        void dr_cb(RdKafka::Message &message);
+};
+
 class Producer {
     public:
         Producer(map<string, string>);
+        // Stops the producer if it's running
+        ~Producer();
 
         optional<string> start();
-        // Returns an error string if error occurred
-        optional<string> produce(const string topic, const optional<string> *key, const map<string, string> *headers,
-            const torustiq_common::ByteBuffer *buffer);
+        // Waits for delivery of pending messages, stops the poll thread and destroys the librdkafka producer
+        void stop();
+        bool is_running();
+        // Waits for the delivery report from broker.
+        // Returns an error string if the message was not delivered
+        optional<string> produce(torustiq_common::ModuleHandle step, const string topic, const optional<string> *key,
+            const map<string, string> *headers, const torustiq_common::ByteBuffer *buffer);
         void rd_producer_lock();
         void rd_producer_unlock();
         void rd_producer_poll();
@@ -34,6 +58,9 @@ class Producer {
     private:
         map<string, string> driver_params;
 
+        DeliveryReportCb delivery_report_cb;
+        atomic<uint64_t> next_message_seq{0};
+        atomic<bool> running{false};
         RdKafka::Producer *rd_producer = nullptr;
         thread rd_producer_poll_thread;
 };
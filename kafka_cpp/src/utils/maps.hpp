@@ -10,12 +10,12 @@ namespace torustiq_kafka_cpp::utils::maps
 {
 
 template<typename T, typename U>
-bool key_exists(T k, map<T, U> m)
+bool key_exists(T k, const map<T, U> &m)
 {
     return m.find(k) != m.end();
 }
 
-bool key_exists(const char *k, map<string, string> m)
+inline bool key_exists(const char *k, const map<string, string> &m)
 {
     return m.find(k) != m.end();
 }
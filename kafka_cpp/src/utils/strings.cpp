@@ -1,3 +1,5 @@
+#include <cstring>
+
 #include "strings.hpp"
 
 namespace torustiq_kafka_cpp::utils::strings
@@ -20,6 +22,35 @@ string replace_all(string str, const string &from, const string &to)
     return str;
 }
 
+const char *to_cchar(const string &str)
+{
+    char *c = new char[str.length() + 1];
+    memcpy(c, str.c_str(), str.length() + 1);
+    return c;
+}
+
+vector<string> split(const string &str, char delimiter)
+{
+    vector<string> parts;
+    size_t start = 0;
+    while (start <= str.length())
+    {
+        size_t end = str.find(delimiter, start);
+        if (end == string::npos)
+        {
+            end = str.length();
+        }
+        string part = str.substr(start, end - start);
+        size_t first = part.find_first_not_of(" \t");
+        if (first != string::npos)
+        {
+            parts.push_back(part.substr(first, part.find_last_not_of(" \t") - first + 1));
+        }
+        start = end + 1;
+    }
+    return parts;
+}
+
 string strip_prefix(string str, string prefix)
 {
     if (!begins_with(str, prefix))
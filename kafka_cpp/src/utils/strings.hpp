@@ -2,13 +2,19 @@
 #define _TORUSTIQ_KAFKA_CPP_UTILS_STRINGS_H_
 
 #include <string>
+#include <vector>
 
 using namespace std;
 
 namespace torustiq_kafka_cpp::utils::strings
 {
 bool begins_with(string str, string substr);
+// Copies a string into a new C string which is passed to the host.
+// The host releases it with torustiq_module_common_free_char
+const char *to_cchar(const string &str);
 string replace_all(string str, const string &from, const string &to);
+// Splits a string by delimiter. Parts are trimmed, empty parts are skipped
+vector<string> split(const string &str, char delimiter);
 string strip_prefix(string str, string prefix);
 }
 
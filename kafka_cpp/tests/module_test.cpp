@@ -0,0 +1,362 @@
+// Loads the module as the host does and runs it against a librdkafka mock cluster.
+// The path to module is passed in TORUSTIQ_MODULE_PATH definition
+#include <chrono>
+#include <condition_variable>
+#include <cstring>
+#include <dlfcn.h>
+#include <functional>
+#include <iostream>
+#include <map>
+#include <mutex>
+#include <optional>
+#include <set>
+#include <string>
+#include <vector>
+
+#include <librdkafka/rdkafka.h>
+#include <librdkafka/rdkafka_mock.h>
+
+#include "../../torustiq_common_typedefs.hpp"
+
+using namespace std;
+using namespace torustiq_common;
+
+const char *TOPIC = "torustiq_test";
+// Kafka protocol API key of ProduceRequest
+const int16_t PRODUCE_API_KEY = 0;
+const auto RECEIVE_TIMEOUT = chrono::seconds(30);
+
+const ModuleHandle DESTINATION_HANDLE = 1;
+const ModuleHandle SOURCE_HANDLE = 2;
+
+// Exported functions of module
+struct Module {
+    void *lib;
+    LibInfo (*get_info)();
+    void (*init)();
+    ModulePipelineConfigureFnResult (*pipeline_configure)(ModulePipelineConfigureArgs);
+    void (*common_set_param)(ModuleHandle, ConstCharPtr, ConstCharPtr);
+    StepStartFnResult (*common_start)(ModuleHandle);
+    void (*common_shutdown)(ModuleHandle);
+    void (*common_free_char)(ConstCharPtr);
+    ModulePipelineProcessRecordFnResult (*pipeline_process_record)(Record, ModuleHandle);
+    void (*pipeline_free_record)(Record);
+};
+
+Module MODULE;
+
+// Host state: terminated steps and records received from the source step
+mutex HOST_MTX;
+condition_variable HOST_CV;
+set<ModuleHandle> TERMINATED_STEPS;
+vector<Record> RECEIVED_RECORDS;
+
+void on_step_terminate(Uint h)
+{
+    lock_guard<mutex> lock(HOST_MTX);
+    TERMINATED_STEPS.insert(h);
+}
+
+void on_data_receive(Record r, ModuleHandle)
+{
+    lock_guard<mutex> lock(HOST_MTX);
+    RECEIVED_RECORDS.push_back(r);
+    HOST_CV.notify_all();
+}
+
+template<typename T>
+T load_fn(const char *name)
+{
+    void *fn = dlsym(MODULE.lib, name);
+    if (nullptr == fn)
+    {
+        cerr << "Function '" << name << "' is not exported by module: " << dlerror() << endl;
+        exit(1);
+    }
+    return reinterpret_cast<T>(fn);
+}
+
+void load_module()
+{
+    MODULE.lib = dlopen(TORUSTIQ_MODULE_PATH, RTLD_NOW);
+    if (nullptr == MODULE.lib)
+    {
+        cerr << "Failed to load the module: " << dlerror() << endl;
+        exit(1);
+    }
+    MODULE.get_info = load_fn<decltype(MODULE.get_info)>("torustiq_module_get_info");
+    MODULE.init = load_fn<decltype(MODULE.init)>("torustiq_module_init");
+    MODULE.pipeline_configure = load_fn<decltype(MODULE.pipeline_configure)>("torustiq_module_pipeline_configure");
+    MODULE.common_set_param = load_fn<decltype(MODULE.common_set_param)>("torustiq_module_common_set_param");
+    MODULE.common_start = load_fn<decltype(MODULE.common_start)>("torustiq_module_common_start");
+    MODULE.common_shutdown = load_fn<decltype(MODULE.common_shutdown)>("torustiq_module_common_shutdown");
+    MODULE.common_free_char = load_fn<decltype(MODULE.common_free_char)>("torustiq_module_common_free_char");
+    MODULE.pipeline_process_record = load_fn<decltype(MODULE.pipeline_process_record)>("torustiq_module_pipeline_process_record");
+    MODULE.pipeline_free_record = load_fn<decltype(MODULE.pipeline_free_record)>("torustiq_module_pipeline_free_record");
+}
+
+// Failed checks are reported, but don't stop the current test
+int NUM_FAILED_CHECKS = 0;
+
+void check(bool condition, const string &description)
+{
+    if (!condition)
+    {
+        cerr << "  FAILED: " << description << endl;
+        NUM_FAILED_CHECKS++;
+    }
+}
+
+ModulePipelineConfigureArgs new_configure_args(PipelineModuleKind kind, ModuleHandle h)
+{
+    return {
+        .kind = kind,
+        .module_handle = h,
+        .on_step_terminate_cb = on_step_terminate,
+        .on_data_receive_cb = on_data_receive,
+    };
+}
+
+void set_params(ModuleHandle h, map<string, string> params)
+{
+    for (pair<const string, string> item: params)
+    {
+        MODULE.common_set_param(h, item.first.c_str(), item.second.c_str());
+    }
+}
+
+// Calls process_record with a record allocated by the host.
+// Returns an error message if the module returned an error
+optional<string> process_record(const string &content, map<string, string> metadata)
+{
+    vector<RecordMetadata> record_metadata;
+    for (pair<const string, string> &item: metadata)
+    {
+        record_metadata.push_back({
+            .name = item.first.c_str(),
+            .value = item.second.c_str(),
+        });
+    }
+    Record r = {
+        .content = {
+            .bytes = (uint8_t *)content.data(),
+            .len = content.length(),
+        },
+        .metadata = {
+            .data = record_metadata.data(),
+            .len = (Uint)record_metadata.size(),
+        },
+    };
+
+    ModulePipelineProcessRecordFnResult res = MODULE.pipeline_process_record(r, DESTINATION_HANDLE);
+    if (res.tag == ModulePipelineProcessRecordFnResult::Tag::Ok)
+    {
+        return nullopt;
+    }
+    string err(res.err._0);
+    MODULE.common_free_char(res.err._0);
+    return err;
+}
+
+map<string, string> get_metadata(Record r)
+{
+    map<string, string> metadata;
+    for (Uint i = 0; i < r.metadata.len; i++)
+    {
+        metadata[r.metadata.data[i].name] = r.metadata.data[i].value;
+    }
+    return metadata;
+}
+
+void test_get_info()
+{
+    LibInfo info = MODULE.get_info();
+    check(info.api_version == CURRENT_API_VERSION, "API version matches the header");
+    check(string(info.id) == "kafka_cpp", "Module ID is 'kafka_cpp'");
+}
+
+void test_configure_unsupported_kind()
+{
+    ModulePipelineConfigureFnResult res = MODULE.pipeline_configure(
+        new_configure_args(PipelineModuleKind::Transformation, 100));
+    check(res.tag == ModulePipelineConfigureFnResult::Tag::ErrorKindNotSupported, "Transformation is not supported");
+}
+
+void test_start_without_params()
+{
+    MODULE.pipeline_configure(new_configure_args(PipelineModuleKind::Destination, 101));
+    StepStartFnResult res = MODULE.common_start(101);
+    check(res.tag == StepStartFnResult::Tag::ErrorMisc, "Step without params fails to start");
+    if (res.tag == StepStartFnResult::Tag::ErrorMisc)
+    {
+        check(string(res.error_misc._0).find("Step params") != string::npos, "Error message mentions step params");
+        MODULE.common_free_char(res.error_misc._0);
+    }
+}
+
+void test_start_invalid_driver_param()
+{
+    MODULE.pipeline_configure(new_configure_args(PipelineModuleKind::Destination, 102));
+    set_params(102, {{"driver.no.such.property", "value"}});
+    StepStartFnResult res = MODULE.common_start(102);
+    check(res.tag == StepStartFnResult::Tag::ErrorMisc, "Step with invalid driver param fails to start");
+    if (res.tag == StepStartFnResult::Tag::ErrorMisc)
+    {
+        MODULE.common_free_char(res.error_misc._0);
+    }
+}
+
+void test_start(const string &bootstrap_servers)
+{
+    MODULE.pipeline_configure(new_configure_args(PipelineModuleKind::Destination, DESTINATION_HANDLE));
+    set_params(DESTINATION_HANDLE, {
+        {"driver.bootstrap.servers", bootstrap_servers},
+        {"driver.message.timeout.ms", "10000"},
+    });
+    StepStartFnResult res = MODULE.common_start(DESTINATION_HANDLE);
+    check(res.tag == StepStartFnResult::Tag::Ok, "Destination step is started");
+
+    MODULE.pipeline_configure(new_configure_args(PipelineModuleKind::Source, SOURCE_HANDLE));
+    set_params(SOURCE_HANDLE, {
+        {"driver.bootstrap.servers", bootstrap_servers},
+        {"driver.group.id", "torustiq_test"},
+        {"driver.auto.offset.reset", "earliest"},
+        {"topics", TOPIC},
+    });
+    res = MODULE.common_start(SOURCE_HANDLE);
+    check(res.tag == StepStartFnResult::Tag::Ok, "Source step is started");
+}
+
+// Waits for the next record consumed by the source step
+optional<Record> receive_record()
+{
+    unique_lock<mutex> lock(HOST_MTX);
+    if (!HOST_CV.wait_for(lock, RECEIVE_TIMEOUT, [] { return !RECEIVED_RECORDS.empty(); }))
+    {
+        return nullopt;
+    }
+    Record r = RECEIVED_RECORDS.front();
+    RECEIVED_RECORDS.erase(RECEIVED_RECORDS.begin());
+    return r;
+}
+
+void test_produce_and_consume()
+{
+    optional<string> err = process_record("Hello, Kafka!", {
+        {"kafka.topic", TOPIC},
+        {"kafka.key", "key-1"},
+        {"kafka.headers.content-type", "text/plain"},
+    });
+    check(nullopt == err, "Record is produced: " + err.value_or(""));
+
+    optional<Record> received = receive_record();
+    check(received.has_value(), "Record is received by the source step");
+    if (!received.has_value())
+    {
+        return;
+    }
+
+    Record r = received.value();
+    check(string((char *)r.content.bytes, r.content.len) == "Hello, Kafka!", "Content is consumed");
+    map<string, string> metadata = get_metadata(r);
+    check(metadata["kafka.topic"] == TOPIC, "kafka.topic is set");
+    check(metadata["kafka.partition"] == "0", "kafka.partition is set");
+    check(metadata["kafka.offset"] == "0", "kafka.offset is set");
+    check(metadata["kafka.key"] == "key-1", "kafka.key is set");
+    check(metadata["kafka.headers.content-type"] == "text/plain", "Headers are mapped to kafka.headers.*");
+
+    MODULE.pipeline_free_record(r);
+}
+
+void test_produce_missing_topic()
+{
+    optional<string> err = process_record("no topic", {});
+    check(nullopt != err, "Record without topic is rejected");
+}
+
+void test_produce_broker_error(rd_kafka_mock_cluster_t *mock_cluster)
+{
+    // Produce requests are retried on retriable errors, so a permanent error is injected
+    rd_kafka_mock_push_request_errors(mock_cluster, PRODUCE_API_KEY, 1, RD_KAFKA_RESP_ERR_MSG_SIZE_TOO_LARGE);
+    optional<string> err = process_record("rejected", {{"kafka.topic", TOPIC}});
+    check(nullopt != err, "Broker-side rejection is returned to the host");
+    check(err.value_or("").find("Failed to deliver") != string::npos, "Error comes from the delivery report: "
+        + err.value_or(""));
+
+    err = process_record("accepted", {{"kafka.topic", TOPIC}});
+    check(nullopt == err, "Record is produced after a rejected one: " + err.value_or(""));
+    optional<Record> received = receive_record();
+    check(received.has_value(), "Record is received by the source step");
+    if (!received.has_value())
+    {
+        return;
+    }
+    Record r = received.value();
+    check(string((char *)r.content.bytes, r.content.len) == "accepted", "Rejected record is not consumed");
+    MODULE.pipeline_free_record(r);
+}
+
+void test_shutdown()
+{
+    MODULE.common_shutdown(DESTINATION_HANDLE);
+    MODULE.common_shutdown(SOURCE_HANDLE);
+    {
+        lock_guard<mutex> lock(HOST_MTX);
+        check(TERMINATED_STEPS.count(DESTINATION_HANDLE) == 1, "Destination step is terminated");
+        check(TERMINATED_STEPS.count(SOURCE_HANDLE) == 1, "Source step is terminated");
+    }
+
+    optional<string> err = process_record("after shutdown", {{"kafka.topic", TOPIC}});
+    check(nullopt != err, "Records are rejected after shutdown");
+}
+
+int main()
+{
+    // A client which owns the mock cluster. The cluster lives until the client is destroyed
+    char errstr[512];
+    rd_kafka_conf_t *conf = rd_kafka_conf_new();
+    if (RD_KAFKA_CONF_OK != rd_kafka_conf_set(conf, "test.mock.num.brokers", "1", errstr, sizeof(errstr)))
+    {
+        cerr << "Failed to configure a mock cluster: " << errstr << endl;
+        return 1;
+    }
+    rd_kafka_t *mock_client = rd_kafka_new(RD_KAFKA_PRODUCER, conf, errstr, sizeof(errstr));
+    if (nullptr == mock_client)
+    {
+        cerr << "Failed to create a Kafka client for mock cluster: " << errstr << endl;
+        return 1;
+    }
+    rd_kafka_mock_cluster_t *mock_cluster = rd_kafka_handle_mock_cluster(mock_client);
+    rd_kafka_mock_topic_create(mock_cluster, TOPIC, 1, 1);
+    string bootstrap_servers = rd_kafka_mock_cluster_bootstraps(mock_cluster);
+
+    load_module();
+    MODULE.init();
+
+    vector<pair<string, function<void()>>> tests = {
+        {"get_info", test_get_info},
+        {"configure_unsupported_kind", test_configure_unsupported_kind},
+        {"start_without_params", test_start_without_params},
+        {"start_invalid_driver_param", test_start_invalid_driver_param},
+        {"start", [&] { test_start(bootstrap_servers); }},
+        {"produce_and_consume", test_produce_and_consume},
+        {"produce_missing_topic", test_produce_missing_topic},
+        {"produce_broker_error", [&] { test_produce_broker_error(mock_cluster); }},
+        {"shutdown", test_shutdown},
+    };
+    int num_failed_tests = 0;
+    for (pair<string, function<void()>> &test: tests)
+    {
+        int num_failed_checks = NUM_FAILED_CHECKS;
+        test.second();
+        bool ok = num_failed_checks == NUM_FAILED_CHECKS;
+        cout << (ok ? "ok      " : "FAILED  ") << test.first << endl;
+        num_failed_tests += ok ? 0 : 1;
+    }
+
+    rd_kafka_destroy(mock_client);
+    dlclose(MODULE.lib);
+
+    cout << tests.size() - num_failed_tests << " passed, " << num_failed_tests << " failed" << endl;
+    return num_failed_tests == 0 ? 0 : 1;
+}
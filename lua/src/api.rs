@@ -0,0 +1,119 @@
+use std::sync::atomic::Ordering;
+
+use log::{debug, error, info, warn};
+use mlua::prelude::*;
+
+use torustiq_common::ffi::types::module::ModuleHandle;
+
+use crate::{
+    helpers::register_helpers,
+    lua_env::{LuaEnvConfig, OutputRecord},
+    state::create_state_table,
+};
+
+/// Creates the 'torustiq' global table:
+/// - torustiq.handle: handle of the current step
+/// - torustiq.params: step params
+/// - torustiq.log.debug/info/warn/error(message)
+/// - torustiq.send(record): passes a {content = ..., metadata = ...} record to the next step
+/// - torustiq.should_stop(): returns true once the step is requested to stop. Source scripts should return from 'run' then
+/// - torustiq.state: persistent key-value state, see state::create_state_table
+/// - native helpers, see helpers::register_helpers
+pub fn register_torustiq_table(lua: &Lua, cfg: &LuaEnvConfig) -> Result<(), LuaError> {
+    let torustiq = lua.create_table()?;
+    torustiq.set("handle", cfg.module_handle)?;
+    torustiq.set("params", cfg.params.clone())?;
+    torustiq.set("log", create_log_table(lua, cfg.module_handle)?)?;
+
+    let module_handle = cfg.module_handle;
+    let sink = cfg.sink.clone();
+    torustiq.set("send", lua.create_function(move |_, record: LuaTable| {
+        let record = OutputRecord::from_lua_table(record)?;
+        sink.send(module_handle, record).map_err(LuaError::RuntimeError)
+    })?)?;
+
+    let stop_signal = cfg.stop_signal.clone();
+    torustiq.set("should_stop", lua.create_function(move |_, ()| Ok(stop_signal.load(Ordering::SeqCst)))?)?;
+
+    torustiq.set("state", create_state_table(lua, cfg.state.clone())?)?;
+    register_helpers(lua, &torustiq)?;
+
+    lua.globals().set("torustiq", torustiq)
+}
+
+fn create_log_table(lua: &Lua, module_handle: ModuleHandle) -> Result<LuaTable<'_>, LuaError> {
+    let log = lua.create_table()?;
+    log.set("debug", lua.create_function(move |_, msg: String| {
+        debug!("Lua step '{}': {}", module_handle, msg);
+        Ok(())
+    })?)?;
+    log.set("info", lua.create_function(move |_, msg: String| {
+        info!("Lua step '{}': {}", module_handle, msg);
+        Ok(())
+    })?)?;
+    log.set("warn", lua.create_function(move |_, msg: String| {
+        warn!("Lua step '{}': {}", module_handle, msg);
+        Ok(())
+    })?)?;
+    log.set("error", lua.create_function(move |_, msg: String| {
+        error!("Lua step '{}': {}", module_handle, msg);
+        Ok(())
+    })?)?;
+    Ok(log)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::{atomic::Ordering, Arc, Mutex}};
+
+    use crate::lua_env::{LuaEnv, LuaEnvConfig, OutputRecord, RecordSink};
+
+    #[test]
+    fn test_torustiq_table() {
+        let cfg = LuaEnvConfig {
+            module_handle: 7,
+            params: HashMap::from([(String::from("threshold"), String::from("10"))]),
+            ..Default::default()
+        };
+        let lua = LuaEnv::try_new(&cfg).unwrap();
+        lua.exec_code(r#"
+            assert(torustiq.handle == 7)
+            assert(torustiq.params.threshold == "10")
+            torustiq.log.info("threshold is " .. torustiq.params.threshold)
+            assert(type(torustiq.send) == "function")
+            assert(torustiq.should_stop() == false)
+        "#).unwrap();
+        cfg.stop_signal.store(true, Ordering::SeqCst);
+        lua.exec_code("assert(torustiq.should_stop() == true)").unwrap();
+    }
+
+    #[test]
+    fn test_torustiq_send() {
+        let captured = Arc::new(Mutex::new(vec![]));
+        let cfg = LuaEnvConfig {
+            sink: RecordSink::Capture(captured.clone()),
+            ..Default::default()
+        };
+        let lua = LuaEnv::try_new(&cfg).unwrap();
+        lua.exec_code(r#"
+            torustiq.send({content = "a\0b", metadata = {key = "k1", attempt = 2}})
+            torustiq.send({content = "no metadata"})
+            assert(not pcall(torustiq.send, {metadata = {key = "k2"}}))
+            assert(not pcall(torustiq.send, {content = "x", metadata = {[1] = "not a key"}}))
+        "#).unwrap();
+
+        assert_eq!(*captured.lock().unwrap(), vec![
+            OutputRecord {
+                content: b"a\0b".to_vec(),
+                metadata: HashMap::from([
+                    (String::from("key"), String::from("k1")),
+                    (String::from("attempt"), String::from("2")),
+                ]),
+            },
+            OutputRecord {
+                content: b"no metadata".to_vec(),
+                metadata: HashMap::new(),
+            },
+        ]);
+    }
+}
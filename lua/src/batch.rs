@@ -0,0 +1,123 @@
+use std::{collections::HashMap, time::{Duration, Instant}};
+
+use crate::lua_env::OutputRecord;
+
+const DEFAULT_BATCH_TIMEOUT_MS: u64 = 100;
+
+/// Records are collected into batches and passed to the 'process_batch' function of module at once
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatchConfig {
+    /// Max number of records in a batch
+    pub size: usize,
+    /// Max time to wait for the batch to fill up, counted from the first record
+    pub timeout: Duration,
+}
+
+impl BatchConfig {
+    /// Reads the batch settings from step parameters. Returns None if batching is not enabled:
+    /// - batch_size: max number of records in a batch. Enables the batch mode
+    /// - batch_timeout_ms: max time to wait for a full batch, 100 by default
+    pub fn from_step_params(params: &HashMap<String, String>) -> Result<Option<Self>, String> {
+        let size = match params.get("batch_size") {
+            Some(v) => v.parse::<usize>().map_err(|e| format!("Invalid value of 'batch_size': '{}': {}", v, e))?,
+            None if params.contains_key("batch_timeout_ms") => {
+                return Err(String::from("'batch_timeout_ms' requires 'batch_size' to be set"))
+            },
+            None => return Ok(None),
+        };
+        if size == 0 {
+            return Err(String::from("'batch_size' must be greater than zero"));
+        }
+        let timeout_ms = match params.get("batch_timeout_ms") {
+            Some(v) => v.parse::<u64>()
+                .map_err(|e| format!("Invalid value of 'batch_timeout_ms': '{}': {}", v, e))?,
+            None => DEFAULT_BATCH_TIMEOUT_MS,
+        };
+        Ok(Some(BatchConfig {
+            size,
+            timeout: Duration::from_millis(timeout_ms),
+        }))
+    }
+}
+
+/// Records collected for the next 'process_batch' call
+pub struct Batch {
+    cfg: BatchConfig,
+    records: Vec<OutputRecord>,
+    started: Option<Instant>,
+}
+
+impl Batch {
+    pub fn new(cfg: BatchConfig) -> Self {
+        Batch {
+            records: Vec::with_capacity(cfg.size),
+            cfg,
+            started: None,
+        }
+    }
+
+    /// Adds a record. Returns true if the batch is full
+    pub fn push(&mut self, record: OutputRecord) -> bool {
+        if self.records.is_empty() {
+            self.started = Some(Instant::now());
+        }
+        self.records.push(record);
+        self.records.len() >= self.cfg.size
+    }
+
+    /// Time left until the batch must be processed, even if it's not full. None if the batch is empty
+    pub fn time_to_deadline(&self) -> Option<Duration> {
+        self.started.map(|s| self.cfg.timeout.saturating_sub(s.elapsed()))
+    }
+
+    /// Returns true if the batch has records and it's full or the timeout has passed
+    pub fn is_ready(&self) -> bool {
+        self.records.len() >= self.cfg.size || self.time_to_deadline() == Some(Duration::ZERO)
+    }
+
+    /// Returns the collected records and starts a new batch
+    pub fn take(&mut self) -> Vec<OutputRecord> {
+        self.started = None;
+        std::mem::replace(&mut self.records, Vec::with_capacity(self.cfg.size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use crate::{lua_env::OutputRecord, test_utils::to_hashmap};
+
+    use super::{Batch, BatchConfig};
+
+    #[test]
+    fn test_from_step_params() {
+        assert_eq!(BatchConfig::from_step_params(&HashMap::new()), Ok(None));
+        assert_eq!(BatchConfig::from_step_params(&to_hashmap(&[("batch_size", "50")])), Ok(Some(BatchConfig {
+            size: 50,
+            timeout: Duration::from_millis(100),
+        })));
+        assert!(BatchConfig::from_step_params(&to_hashmap(&[("batch_size", "0")])).is_err());
+        assert!(BatchConfig::from_step_params(&to_hashmap(&[("batch_timeout_ms", "10")])).is_err());
+    }
+
+    #[test]
+    fn test_batch() {
+        let mut batch = Batch::new(BatchConfig {
+            size: 2,
+            timeout: Duration::ZERO,
+        });
+        let record = || OutputRecord {
+            content: vec![],
+            metadata: HashMap::new(),
+        };
+        assert!(!batch.is_ready());
+        assert_eq!(batch.time_to_deadline(), None);
+        assert!(!batch.push(record()));
+        // The timeout has passed
+        assert!(batch.is_ready());
+        assert!(batch.push(record()));
+        assert_eq!(batch.take().len(), 2);
+        assert!(!batch.is_ready());
+    }
+}
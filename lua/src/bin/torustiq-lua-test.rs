@@ -0,0 +1,58 @@
+//! Tests a Lua processor without a pipeline:
+//!
+//! torustiq-lua-test <script.lua> <input.jsonl> [expected.jsonl] [--param key=value]...
+//!
+//! Records emitted by the script are compared with the expected ones. If the expected file is not set,
+//! the emitted records are printed as JSON lines, so the output can be reviewed and saved as the expected file
+
+use std::{collections::HashMap, env, fs, process::ExitCode};
+
+use torustiq_lua::testing::{compare_records, read_records, record_to_json, run_script};
+
+const USAGE: &str = "Usage: torustiq-lua-test <script.lua> <input.jsonl> [expected.jsonl] [--param key=value]...";
+
+fn main() -> ExitCode {
+    match run(env::args().skip(1).collect()) {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        },
+    }
+}
+
+fn run(args: Vec<String>) -> Result<(), String> {
+    let mut files = vec![];
+    let mut params = HashMap::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg != "--param" {
+            files.push(arg);
+            continue
+        }
+        let param = match args.next() {
+            Some(p) => p,
+            None => return Err(format!("'--param' requires a value\n{}", USAGE)),
+        };
+        match param.split_once('=') {
+            Some((k, v)) => params.insert(String::from(k), String::from(v)),
+            None => return Err(format!("Invalid param '{}'. Expected key=value", param)),
+        };
+    }
+    let (script, input, expected) = match files.as_slice() {
+        [s, i] => (s, i, None),
+        [s, i, e] => (s, i, Some(e)),
+        _ => return Err(String::from(USAGE)),
+    };
+
+    let code = fs::read(script).map_err(|e| format!("Failed to read '{}': {}", script, e))?;
+    let output = run_script(&code, params, read_records(input)?)?;
+    match expected {
+        Some(e) => {
+            compare_records(&output, &read_records(e)?)?;
+            println!("OK: {} record(s) match '{}'", output.len(), e);
+        },
+        None => output.iter().for_each(|r| println!("{}", record_to_json(r))),
+    }
+    Ok(())
+}
@@ -0,0 +1,222 @@
+use std::{collections::HashMap, fs, path::{Path, PathBuf}};
+
+use log::warn;
+use mlua::{ChunkMode, Function, prelude::*};
+use sha2::{Digest, Sha256};
+
+/// Binary chunks produced by luac or string.dump start with this signature
+const LUA_SIGNATURE: &[u8] = b"\x1bLua";
+/// Bytecode is not compatible between Lua versions, so cached chunks of other versions are not picked up
+const CACHE_FILE_PREFIX: &str = "lua54-";
+/// Cache files start with the hash of source code and the hash of bytecode
+const CACHE_HEADER_LEN: usize = 64;
+
+type SourceHash = sha2::digest::Output<Sha256>;
+
+/// How Lua code is loaded
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChunkConfig {
+    /// Allows precompiled bytecode. Malformed bytecode can crash the Lua VM, so it must come from a trusted source
+    pub allow_bytecode: bool,
+    /// A directory to keep compiled chunks in. Scripts are not parsed again if they haven't changed.
+    /// Cached chunks are bytecode too, so the cache requires `allow_bytecode`
+    pub cache_dir: Option<PathBuf>,
+}
+
+impl ChunkConfig {
+    /// Reads the code loading settings from step parameters:
+    /// - allow_bytecode: 'true' or 'false' (default)
+    /// - bytecode_cache_dir: a directory for compiled chunks. The cache is disabled if not set
+    pub fn from_step_params(params: &HashMap<String, String>) -> Result<Self, String> {
+        let allow_bytecode = match params.get("allow_bytecode").map(String::as_str) {
+            None | Some("false") => false,
+            Some("true") => true,
+            Some(v) => return Err(format!("Invalid value of 'allow_bytecode': '{}'. Expected 'true' or 'false'", v)),
+        };
+        let cache_dir = params.get("bytecode_cache_dir").map(PathBuf::from);
+        if cache_dir.is_some() && !allow_bytecode {
+            return Err(String::from("'bytecode_cache_dir' requires 'allow_bytecode' to be 'true': cached chunks are loaded as bytecode"));
+        }
+        Ok(ChunkConfig {
+            allow_bytecode,
+            cache_dir,
+        })
+    }
+}
+
+/// Compiles Lua code into a function. Like Chunk::eval, source code is compiled as an expression first
+/// if `expression` is set, e.g. 'function (...) end', and as a block otherwise.
+/// Bytecode is loaded only if it's allowed. Compiled source code is stored in the cache directory, if it's set.
+/// Stale or corrupted cache entries are compiled again. The hashes don't protect against deliberate changes,
+/// so the cache directory must be trusted like the bytecode itself
+pub fn compile<'lua>(lua: &'lua Lua, cfg: &ChunkConfig, name: &str, code: &[u8],
+    expression: bool) -> Result<Function<'lua>, String> {
+    if code.starts_with(LUA_SIGNATURE) {
+        if !cfg.allow_bytecode {
+            return Err(String::from("Lua bytecode is not allowed. Set 'allow_bytecode' to 'true' to load precompiled code"));
+        }
+        return lua.load(code).set_name(name).set_mode(ChunkMode::Binary).into_function()
+            .map_err(|e| format!("Failed to load Lua bytecode: {}", e));
+    }
+
+    let source_hash = source_hash(name, code, expression);
+    let cache_path = match (&cfg.cache_dir, cfg.allow_bytecode) {
+        (Some(d), true) => Some(d.join(format!("{}{:x}.luac", CACHE_FILE_PREFIX, source_hash))),
+        _ => None,
+    };
+    // A stale or corrupted cache file is ignored and overwritten
+    if let Some(Ok(contents)) = cache_path.as_ref().map(fs::read) {
+        match verify_cached_chunk(&contents, &source_hash) {
+            Some(bytecode) => match lua.load(bytecode).set_name(name).set_mode(ChunkMode::Binary).into_function() {
+                Ok(f) => return Ok(f),
+                Err(e) => warn!("Failed to load the cached Lua chunk '{}': {}", name, e),
+            },
+            None => warn!("The cached Lua chunk of '{}' doesn't match the source code. Compiling it again", name),
+        }
+    }
+
+    let load_text = |source: &[u8]| lua.load(source).set_name(name).set_mode(ChunkMode::Text).into_function();
+    let func = match expression {
+        true => load_text(&[b"return ", code].concat()).or_else(|_| load_text(code)),
+        false => load_text(code),
+    }.map_err(|e| format!("Failed to compile Lua code: {}", e))?;
+
+    if let Some(p) = cache_path {
+        if let Err(e) = store_cached_chunk(&p, &source_hash, &func.dump(false)) {
+            warn!("Failed to store the compiled Lua code in '{}': {}", p.display(), e);
+        }
+    }
+    Ok(func)
+}
+
+/// Replaces the cache file atomically, so other steps never read a partially written chunk.
+/// The bytecode is prefixed with the hashes of source code and bytecode, see verify_cached_chunk
+fn store_cached_chunk(path: &Path, source_hash: &SourceHash, bytecode: &[u8]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, [source_hash.as_slice(), Sha256::digest(bytecode).as_slice(), bytecode].concat())?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Returns the bytecode of a cache file if it was compiled from the same source code and isn't corrupted.
+/// Anyone who can write to the cache directory can compute both hashes, so this is not a protection against tampering
+fn verify_cached_chunk<'a>(contents: &'a [u8], source_hash: &SourceHash) -> Option<&'a [u8]> {
+    if contents.len() < CACHE_HEADER_LEN {
+        return None;
+    }
+    let (header, bytecode) = contents.split_at(CACHE_HEADER_LEN);
+    let (cached_source_hash, bytecode_hash) = header.split_at(CACHE_HEADER_LEN / 2);
+    match cached_source_hash == source_hash.as_slice() && bytecode_hash == Sha256::digest(bytecode).as_slice() {
+        true => Some(bytecode),
+        false => None,
+    }
+}
+
+/// Cache files are named after the hash of source code, so changed code is compiled again
+fn source_hash(name: &str, code: &[u8], expression: bool) -> SourceHash {
+    let mut hasher = Sha256::new();
+    hasher.update(name.as_bytes());
+    hasher.update([0, expression as u8]);
+    hasher.update(code);
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use mlua::{Function, Lua};
+
+    use crate::test_utils::{to_hashmap, TempDir};
+
+    use super::{compile, ChunkConfig};
+
+    #[test]
+    fn test_bytecode() {
+        let lua = Lua::new();
+        let bytecode = lua.load("return function (x) return x * 2 end").into_function().unwrap().dump(true);
+
+        assert!(compile(&lua, &ChunkConfig::default(), "=test", &bytecode, true).is_err());
+        let cfg = ChunkConfig {
+            allow_bytecode: true,
+            cache_dir: None,
+        };
+        let double: Function = compile(&lua, &cfg, "=test", &bytecode, true).unwrap().call(()).unwrap();
+        assert_eq!(double.call::<_, i64>(21).unwrap(), 42);
+    }
+
+    #[test]
+    fn test_from_step_params() {
+        assert_eq!(ChunkConfig::from_step_params(&to_hashmap(&[])), Ok(ChunkConfig::default()));
+        assert_eq!(ChunkConfig::from_step_params(&to_hashmap(&[
+            ("allow_bytecode", "true"),
+            ("bytecode_cache_dir", "/tmp/cache"),
+        ])), Ok(ChunkConfig {
+            allow_bytecode: true,
+            cache_dir: Some("/tmp/cache".into()),
+        }));
+        // Cached chunks are bytecode, so the cache is not available without 'allow_bytecode'
+        assert!(ChunkConfig::from_step_params(&to_hashmap(&[("bytecode_cache_dir", "/tmp/cache")])).is_err());
+        assert!(ChunkConfig::from_step_params(&to_hashmap(&[("allow_bytecode", "yes")])).is_err());
+    }
+
+    #[test]
+    fn test_cache() {
+        let dir = TempDir::new("chunk_cache");
+        let cfg = ChunkConfig {
+            allow_bytecode: true,
+            cache_dir: Some(dir.join("cache")),
+        };
+        let lua = Lua::new();
+        let code = b"function (x) return x + 1 end";
+
+        let f: Function = compile(&lua, &cfg, "=test", code, true).unwrap().call(()).unwrap();
+        assert_eq!(f.call::<_, i64>(1).unwrap(), 2);
+        let cached: Vec<_> = fs::read_dir(dir.join("cache")).unwrap().map(|e| e.unwrap().path()).collect();
+        assert_eq!(cached.len(), 1);
+
+        let f: Function = compile(&lua, &cfg, "=test", code, true).unwrap().call(()).unwrap();
+        assert_eq!(f.call::<_, i64>(2).unwrap(), 3);
+
+        // A broken cache file is replaced
+        fs::write(&cached[0], b"\x1bLua broken").unwrap();
+        let f: Function = compile(&lua, &cfg, "=test", code, true).unwrap().call(()).unwrap();
+        assert_eq!(f.call::<_, i64>(3).unwrap(), 4);
+        assert_ne!(fs::read(&cached[0]).unwrap(), b"\x1bLua broken");
+
+        // The cache is not used if bytecode is not allowed
+        let no_bytecode = ChunkConfig {
+            allow_bytecode: false,
+            cache_dir: Some(dir.join("cache")),
+        };
+        fs::remove_file(&cached[0]).unwrap();
+        compile(&lua, &no_bytecode, "=test", code, true).unwrap();
+        assert!(!cached[0].exists());
+    }
+
+    #[test]
+    fn test_cache_mismatch() {
+        let dir = TempDir::new("chunk_cache_mismatch");
+        let cfg = ChunkConfig {
+            allow_bytecode: true,
+            cache_dir: Some(dir.join("cache")),
+        };
+        let lua = Lua::new();
+        let code = b"function (x) return x + 1 end";
+        compile(&lua, &cfg, "=test", code, true).unwrap();
+        let cached: Vec<_> = fs::read_dir(dir.join("cache")).unwrap().map(|e| e.unwrap().path()).collect();
+        let contents = fs::read(&cached[0]).unwrap();
+        let other = lua.load("return function (x) return 666 end").into_function().unwrap().dump(false);
+
+        // Valid bytecode of other code: both without the header and with the header of the original bytecode
+        for mismatched in [other.clone(), [&contents[..64], other.as_slice()].concat()] {
+            fs::write(&cached[0], &mismatched).unwrap();
+            let f: Function = compile(&lua, &cfg, "=test", code, true).unwrap().call(()).unwrap();
+            assert_eq!(f.call::<_, i64>(1).unwrap(), 2);
+            assert_eq!(fs::read(&cached[0]).unwrap(), contents);
+        }
+    }
+}
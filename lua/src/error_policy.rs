@@ -0,0 +1,108 @@
+use std::{collections::HashMap, time::Duration};
+
+const DEFAULT_RETRY_MAX_ATTEMPTS: u32 = 3;
+const DEFAULT_RETRY_BACKOFF_MS: u64 = 100;
+
+/// What to do with a record if Lua code fails to process it
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum ErrorPolicy {
+    /// The record is dropped, the error is logged
+    #[default]
+    Drop,
+    /// The original record is passed to the next step with the error in 'lua.error' metadata field
+    ForwardOriginal,
+    /// The step is stopped
+    Terminate,
+    /// The record is processed again. The delay is doubled after each attempt.
+    /// The record is dropped once all attempts failed
+    Retry {
+        max_attempts: u32,
+        backoff: Duration,
+    },
+}
+
+impl ErrorPolicy {
+    /// Reads the error policy from step parameters:
+    /// - on_error: 'drop' (default), 'forward_original', 'terminate' or 'retry'
+    /// - on_error.max_attempts: for 'retry', 3 by default
+    /// - on_error.backoff_ms: for 'retry', delay before the second attempt, 100 by default
+    pub fn from_step_params(params: &HashMap<String, String>) -> Result<Self, String> {
+        match params.get("on_error").map(String::as_str) {
+            None | Some("drop") => Ok(ErrorPolicy::Drop),
+            Some("forward_original") => Ok(ErrorPolicy::ForwardOriginal),
+            Some("terminate") => Ok(ErrorPolicy::Terminate),
+            Some("retry") => {
+                let max_attempts = match params.get("on_error.max_attempts") {
+                    Some(v) => v.parse::<u32>()
+                        .map_err(|e| format!("Invalid value of 'on_error.max_attempts': '{}': {}", v, e))?,
+                    None => DEFAULT_RETRY_MAX_ATTEMPTS,
+                };
+                if max_attempts == 0 {
+                    return Err(String::from("'on_error.max_attempts' must be greater than zero"));
+                }
+                let backoff_ms = match params.get("on_error.backoff_ms") {
+                    Some(v) => v.parse::<u64>()
+                        .map_err(|e| format!("Invalid value of 'on_error.backoff_ms': '{}': {}", v, e))?,
+                    None => DEFAULT_RETRY_BACKOFF_MS,
+                };
+                Ok(ErrorPolicy::Retry {
+                    max_attempts,
+                    backoff: Duration::from_millis(backoff_ms),
+                })
+            },
+            Some(v) => Err(format!("Invalid value of 'on_error': '{}'. \
+                Expected 'drop', 'forward_original', 'terminate' or 'retry'", v)),
+        }
+    }
+
+    /// Returns a delay before the next attempt or None if the record shouldn't be processed again.
+    /// Attempts are counted from 1
+    pub fn retry_delay(&self, failed_attempt: u32) -> Option<Duration> {
+        match self {
+            ErrorPolicy::Retry { max_attempts, backoff } if failed_attempt < *max_attempts => {
+                Some(backoff.saturating_mul(2u32.saturating_pow(failed_attempt - 1)))
+            },
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use crate::test_utils::to_hashmap;
+
+    use super::ErrorPolicy;
+
+    #[test]
+    fn test_from_step_params() {
+        assert_eq!(ErrorPolicy::from_step_params(&HashMap::new()), Ok(ErrorPolicy::Drop));
+        assert_eq!(ErrorPolicy::from_step_params(&to_hashmap(&[("on_error", "forward_original")])),
+            Ok(ErrorPolicy::ForwardOriginal));
+        assert_eq!(ErrorPolicy::from_step_params(&to_hashmap(&[
+            ("on_error", "retry"),
+            ("on_error.max_attempts", "5"),
+        ])), Ok(ErrorPolicy::Retry {
+            max_attempts: 5,
+            backoff: Duration::from_millis(100),
+        }));
+        assert!(ErrorPolicy::from_step_params(&to_hashmap(&[("on_error", "ignore")])).is_err());
+        assert!(ErrorPolicy::from_step_params(&to_hashmap(&[
+            ("on_error", "retry"),
+            ("on_error.max_attempts", "0"),
+        ])).is_err());
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let policy = ErrorPolicy::Retry {
+            max_attempts: 3,
+            backoff: Duration::from_millis(100),
+        };
+        assert_eq!(policy.retry_delay(1), Some(Duration::from_millis(100)));
+        assert_eq!(policy.retry_delay(2), Some(Duration::from_millis(200)));
+        assert_eq!(policy.retry_delay(3), None);
+        assert_eq!(ErrorPolicy::Drop.retry_delay(1), None);
+    }
+}
@@ -0,0 +1,265 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use mlua::{prelude::*, Variadic};
+use regex::bytes::Regex;
+use serde_json::{Map, Number, Value};
+use sha2::{Digest, Sha256};
+use xxhash_rust::xxh64::xxh64;
+
+/// Protects from cyclic tables
+const JSON_MAX_DEPTH: usize = 128;
+/// Compiled regular expressions are reused. The cache is cleared once it reaches this size
+const REGEX_CACHE_SIZE: usize = 256;
+
+/// Adds native helpers to the 'torustiq' table:
+/// - torustiq.json.decode(s), torustiq.json.encode(v), torustiq.json.null
+/// - torustiq.base64.encode(s), torustiq.base64.decode(s)
+/// - torustiq.hash.sha256(s), torustiq.hash.xxhash(s[, seed]): hex strings
+/// - torustiq.re.match(s, pattern), torustiq.re.replace(s, pattern, replacement)
+pub fn register_helpers<'lua>(lua: &'lua Lua, torustiq: &LuaTable<'lua>) -> Result<(), LuaError> {
+    torustiq.set("json", create_json_table(lua)?)?;
+    torustiq.set("base64", create_base64_table(lua)?)?;
+    torustiq.set("hash", create_hash_table(lua)?)?;
+    torustiq.set("re", create_re_table(lua)?)?;
+    Ok(())
+}
+
+fn create_json_table(lua: &Lua) -> Result<LuaTable<'_>, LuaError> {
+    let json = lua.create_table()?;
+    // JSON null is decoded to this value, as nil values can't be stored in tables
+    json.set("null", LuaValue::NULL)?;
+    json.set("decode", lua.create_function(|lua, s: LuaString| {
+        match serde_json::from_slice::<Value>(s.as_bytes()) {
+            Ok(v) => json_to_lua(lua, &v),
+            Err(e) => Err(LuaError::RuntimeError(format!("Failed to decode JSON: {}", e))),
+        }
+    })?)?;
+    json.set("encode", lua.create_function(|_, v: LuaValue| {
+        match lua_to_json(&v) {
+            Ok(v) => Ok(v.to_string()),
+            Err(e) => Err(LuaError::RuntimeError(format!("Failed to encode JSON: {}", e))),
+        }
+    })?)?;
+    Ok(json)
+}
+
+pub fn json_to_lua<'lua>(lua: &'lua Lua, value: &Value) -> Result<LuaValue<'lua>, LuaError> {
+    Ok(match value {
+        Value::Null => LuaValue::NULL,
+        Value::Bool(b) => LuaValue::Boolean(*b),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => LuaValue::Integer(i),
+            None => LuaValue::Number(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        Value::String(s) => LuaValue::String(lua.create_string(s)?),
+        Value::Array(items) => {
+            let t = lua.create_table_with_capacity(items.len(), 0)?;
+            for (i, item) in items.iter().enumerate() {
+                t.raw_set(i + 1, json_to_lua(lua, item)?)?;
+            }
+            LuaValue::Table(t)
+        },
+        Value::Object(fields) => {
+            let t = lua.create_table_with_capacity(0, fields.len())?;
+            for (k, v) in fields {
+                t.raw_set(k.as_str(), json_to_lua(lua, v)?)?;
+            }
+            LuaValue::Table(t)
+        },
+    })
+}
+
+/// Converts a Lua value into JSON. Sequences become arrays, other tables become objects
+pub fn lua_to_json(value: &LuaValue<'_>) -> Result<Value, String> {
+    lua_to_json_with_depth(value, 0)
+}
+
+fn lua_to_json_with_depth(value: &LuaValue<'_>, depth: usize) -> Result<Value, String> {
+    if depth > JSON_MAX_DEPTH {
+        return Err(format!("tables are nested deeper than {} levels. Is there a cycle?", JSON_MAX_DEPTH));
+    }
+    match value {
+        LuaValue::Nil => Ok(Value::Null),
+        v if v.is_null() => Ok(Value::Null),
+        LuaValue::Boolean(b) => Ok(Value::Bool(*b)),
+        LuaValue::Integer(i) => Ok(Value::from(*i)),
+        LuaValue::Number(n) => match Number::from_f64(*n) {
+            Some(n) => Ok(Value::Number(n)),
+            None => Err(format!("number {} can't be represented in JSON", n)),
+        },
+        LuaValue::String(s) => match s.to_str() {
+            Ok(s) => Ok(Value::String(String::from(s))),
+            Err(_) => Err(String::from("string is not valid UTF-8")),
+        },
+        LuaValue::Table(t) => table_to_json(t, depth),
+        v => Err(format!("value of type '{}' can't be represented in JSON", v.type_name())),
+    }
+}
+
+fn table_to_json(t: &LuaTable<'_>, depth: usize) -> Result<Value, String> {
+    let mut items = vec![];
+    for pair in t.clone().pairs::<LuaValue, LuaValue>() {
+        items.push(pair.map_err(|e| e.to_string())?);
+    }
+
+    let len = t.raw_len();
+    let is_array = len > 0 && items.len() == len && items.iter()
+        .all(|(k, _)| matches!(k, LuaValue::Integer(i) if *i >= 1 && *i as usize <= len));
+    if is_array {
+        let mut array = vec![Value::Null; len];
+        for (k, v) in items {
+            if let LuaValue::Integer(i) = k {
+                array[i as usize - 1] = lua_to_json_with_depth(&v, depth + 1)?;
+            }
+        }
+        return Ok(Value::Array(array));
+    }
+
+    let mut object = Map::new();
+    for (k, v) in items {
+        let k = match k {
+            LuaValue::String(s) => match s.to_str() {
+                Ok(s) => String::from(s),
+                Err(_) => return Err(String::from("table key is not valid UTF-8")),
+            },
+            LuaValue::Integer(i) => i.to_string(),
+            LuaValue::Number(n) => n.to_string(),
+            k => return Err(format!("table key of type '{}' can't be represented in JSON", k.type_name())),
+        };
+        let v = lua_to_json_with_depth(&v, depth + 1).map_err(|e| format!("{}: {}", k, e))?;
+        object.insert(k, v);
+    }
+    Ok(Value::Object(object))
+}
+
+fn create_base64_table(lua: &Lua) -> Result<LuaTable<'_>, LuaError> {
+    let base64 = lua.create_table()?;
+    base64.set("encode", lua.create_function(|_, s: LuaString| Ok(STANDARD.encode(s.as_bytes())))?)?;
+    base64.set("decode", lua.create_function(|lua, s: LuaString| {
+        match STANDARD.decode(s.as_bytes()) {
+            Ok(b) => lua.create_string(b),
+            Err(e) => Err(LuaError::RuntimeError(format!("Failed to decode base64: {}", e))),
+        }
+    })?)?;
+    Ok(base64)
+}
+
+fn create_hash_table(lua: &Lua) -> Result<LuaTable<'_>, LuaError> {
+    let hash = lua.create_table()?;
+    hash.set("sha256", lua.create_function(|_, s: LuaString| {
+        Ok(format!("{:x}", Sha256::digest(s.as_bytes())))
+    })?)?;
+    hash.set("xxhash", lua.create_function(|_, (s, seed): (LuaString, Option<u64>)| {
+        Ok(format!("{:016x}", xxh64(s.as_bytes(), seed.unwrap_or(0))))
+    })?)?;
+    Ok(hash)
+}
+
+fn create_re_table(lua: &Lua) -> Result<LuaTable<'_>, LuaError> {
+    let re = lua.create_table()?;
+    let cache = Rc::new(RefCell::new(HashMap::new()));
+
+    let match_cache = cache.clone();
+    // Returns the capture groups like string.match does: the whole match if there are no groups, nil if no match
+    re.set("match", lua.create_function(move |lua, (s, pattern): (LuaString, String)| {
+        let regex = compile_regex(&match_cache, &pattern)?;
+        let mut result = Variadic::new();
+        let captures = match regex.captures(s.as_bytes()) {
+            Some(c) => c,
+            None => {
+                result.push(LuaNil);
+                return Ok(result)
+            },
+        };
+        let groups: Vec<_> = match captures.len() {
+            1 => vec![captures.get(0)],
+            _ => captures.iter().skip(1).collect(),
+        };
+        for group in groups {
+            result.push(match group {
+                Some(m) => LuaValue::String(lua.create_string(m.as_bytes())?),
+                None => LuaNil,
+            });
+        }
+        Ok(result)
+    })?)?;
+
+    // Replaces all matches. Groups are referenced as $1 or ${name} in replacement
+    re.set("replace", lua.create_function(move |lua, (s, pattern, replacement): (LuaString, String, LuaString)| {
+        let regex = compile_regex(&cache, &pattern)?;
+        lua.create_string(regex.replace_all(s.as_bytes(), replacement.as_bytes()))
+    })?)?;
+    Ok(re)
+}
+
+fn compile_regex(cache: &RefCell<HashMap<String, Regex>>, pattern: &str) -> Result<Regex, LuaError> {
+    if let Some(r) = cache.borrow().get(pattern) {
+        return Ok(r.clone());
+    }
+    let regex = match Regex::new(pattern) {
+        Ok(r) => r,
+        Err(e) => return Err(LuaError::RuntimeError(format!("Invalid regular expression '{}': {}", pattern, e))),
+    };
+    let mut cache = cache.borrow_mut();
+    if cache.len() >= REGEX_CACHE_SIZE {
+        cache.clear();
+    }
+    cache.insert(String::from(pattern), regex.clone());
+    Ok(regex)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lua_env::{LuaEnv, LuaEnvConfig};
+
+    #[test]
+    fn test_json() {
+        let lua = LuaEnv::try_new(&LuaEnvConfig::default()).unwrap();
+        lua.exec_code(r#"
+            local v = torustiq.json.decode('{"a": [1, 2.5, null, "x"], "b": {"c": true}, "d": null}')
+            assert(v.a[1] == 1 and math.type(v.a[1]) == "integer")
+            assert(v.a[2] == 2.5 and v.a[3] == torustiq.json.null and v.a[4] == "x")
+            assert(v.b.c == true and v.d == torustiq.json.null)
+
+            assert(torustiq.json.encode({1, 2, "three"}) == '[1,2,"three"]')
+            assert(torustiq.json.encode({b = {c = false}, a = torustiq.json.null}) == '{"a":null,"b":{"c":false}}')
+            assert(torustiq.json.encode(torustiq.json.decode('{"a":[1,null]}')) == '{"a":[1,null]}')
+
+            local cyclic = {}
+            cyclic.self = cyclic
+            assert(not pcall(torustiq.json.encode, cyclic))
+            assert(not pcall(torustiq.json.encode, {f = print}))
+            assert(not pcall(torustiq.json.decode, '{"a":'))
+        "#).unwrap();
+    }
+
+    #[test]
+    fn test_base64_and_hash() {
+        let lua = LuaEnv::try_new(&LuaEnvConfig::default()).unwrap();
+        lua.exec_code(r#"
+            assert(torustiq.base64.encode("hello\0\255") == "aGVsbG8A/w==")
+            assert(torustiq.base64.decode("aGVsbG8A/w==") == "hello\0\255")
+            assert(not pcall(torustiq.base64.decode, "not base64!"))
+
+            assert(torustiq.hash.sha256("abc") == "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+            assert(torustiq.hash.xxhash("") == "ef46db3751d8e999")
+            assert(torustiq.hash.xxhash("abc") ~= torustiq.hash.xxhash("abc", 1))
+        "#).unwrap();
+    }
+
+    #[test]
+    fn test_regex() {
+        let lua = LuaEnv::try_new(&LuaEnvConfig::default()).unwrap();
+        lua.exec_code(r##"
+            assert(torustiq.re.match("order-123", "\\d+") == "123")
+            local k, v = torustiq.re.match("user=alice", "(\\w+)=(\\w+)")
+            assert(k == "user" and v == "alice")
+            assert(torustiq.re.match("abc", "\\d") == nil)
+
+            assert(torustiq.re.replace("a1b22c", "\\d+", "#") == "a#b#c")
+            assert(torustiq.re.replace("2024-01-31", "(\\d+)-(\\d+)-(\\d+)", "$3.$2.$1") == "31.01.2024")
+            assert(not pcall(torustiq.re.match, "abc", "("))
+        "##).unwrap();
+    }
+}
@@ -1,11 +1,30 @@
+mod api;
+mod batch;
+mod chunks;
+mod error_policy;
+mod helpers;
 mod lua_env;
+mod packages;
+mod state;
+#[cfg(test)]
+mod test_utils;
+pub mod testing;
 mod threads;
+mod watch;
+mod workers;
 
-use std::{fs, thread};
+use std::{
+    collections::HashMap,
+    fs,
+    sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex},
+    thread,
+};
+
+use once_cell::sync::Lazy;
 
 use torustiq_common::{
     ffi::{
-        shared::{get_common_lib_configuration, get_param, get_pipeline_module_configuration, set_pipeline_module_configuration},
+        shared::{get_common_lib_configuration, get_param, get_params, get_pipeline_module_configuration, set_pipeline_module_configuration},
         types::module::{
             LibInfo, ModuleHandle, ModuleKind, ModulePipelineConfigureArgs,
             ModulePipelineConfigureFnResult, PipelineModuleKind, StepStartFnResult
@@ -16,6 +35,11 @@ use torustiq_common::{
     CURRENT_API_VERSION,
 };
 
+use crate::{batch::BatchConfig, error_policy::ErrorPolicy, lua_env::LuaEnvConfig, workers::WorkerPoolConfig};
+
+/// Stop signals of running steps, see LuaEnvConfig::stop_signal
+static STOP_SIGNALS: Lazy<Mutex<HashMap<ModuleHandle, Arc<AtomicBool>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
 const MODULE_INFO: LibInfo = LibInfo {
     api_version: CURRENT_API_VERSION,
     id: c"lua".as_ptr(),
@@ -45,27 +69,86 @@ extern "C" fn torustiq_module_common_start(handle: ModuleHandle) -> StepStartFnR
 
     let code = match get_param(handle, "file") {
         Some(f) => {
-            match fs::read_to_string(f.clone()) {
+            match fs::read(f.clone()) {
                 Ok(c) => c,
                 Err(e) => return StepStartFnResult::ErrorMisc(
                     string_to_cchar(format!("Failed to read contents of Lua file '{}': {}", f, e)))
             }
         },
         None => match get_param(handle, "code_contents") {
-            Some(c) => c,
+            Some(c) => c.into_bytes(),
             None => return StepStartFnResult::ErrorMisc(
                 string_to_cchar("Either 'file' or 'code_contents' attribute must be provided for Lua handler")),
         }
     };
+    let params = get_params(handle).unwrap_or_default();
+    let pool_cfg = match WorkerPoolConfig::from_step_params(&params) {
+        Ok(c) => c,
+        Err(e) => return StepStartFnResult::ErrorMisc(string_to_cchar(e)),
+    };
+    let error_policy = match ErrorPolicy::from_step_params(&params) {
+        Ok(p) => p,
+        Err(e) => return StepStartFnResult::ErrorMisc(string_to_cchar(e)),
+    };
+    let batch_cfg = match BatchConfig::from_step_params(&params) {
+        Ok(c) => c,
+        Err(e) => return StepStartFnResult::ErrorMisc(string_to_cchar(e)),
+    };
+    if batch_cfg.is_some() && args.kind == PipelineModuleKind::Source {
+        return StepStartFnResult::ErrorMisc(string_to_cchar("'batch_size' is not supported in source steps"));
+    }
+    let watch_file = match params.get("watch").map(String::as_str) {
+        None | Some("false") => None,
+        Some("true") => match (params.get("file"), &args.kind) {
+            (_, PipelineModuleKind::Source) => return StepStartFnResult::ErrorMisc(
+                string_to_cchar("Reloading of Lua code with 'watch' is not supported in source steps")),
+            (Some(f), _) => Some(f.clone()),
+            (None, _) => return StepStartFnResult::ErrorMisc(
+                string_to_cchar("Reloading of Lua code with 'watch' requires the 'file' attribute")),
+        },
+        Some(v) => return StepStartFnResult::ErrorMisc(
+            string_to_cchar(format!("Invalid value of 'watch': '{}'. Expected 'true' or 'false'", v))),
+    };
+    let env_cfg = match LuaEnvConfig::from_step_params(handle, params) {
+        Ok(c) => c,
+        Err(e) => return StepStartFnResult::ErrorMisc(string_to_cchar(e)),
+    };
+    let stop_signal = env_cfg.stop_signal.clone();
+    STOP_SIGNALS.lock().unwrap().insert(handle, stop_signal.clone());
     let args_kind = args.kind.clone();
     let thread_args = threads::ThreadArgs {
         code,
         lib_args,
         module_args: args,
+        env_cfg,
+        pool_cfg,
+        error_policy,
+        batch_cfg,
+        watch_file,
     };
-    match args_kind {
-        PipelineModuleKind::Source => thread::spawn(|| threads::thread_source(thread_args)),
-        _ => thread::spawn(|| threads::thread_processor(thread_args)),
-    };
+    thread::spawn(move || {
+        match args_kind {
+            PipelineModuleKind::Source => threads::thread_source(thread_args),
+            _ => threads::thread_processor(thread_args),
+        };
+        remove_stop_signal(handle, &stop_signal);
+    });
     StepStartFnResult::Ok
-}
\ No newline at end of file
+}
+
+/// Forgets the stop signal of a finished step. The step might be started again meanwhile, so a newer signal is kept
+fn remove_stop_signal(handle: ModuleHandle, stop_signal: &Arc<AtomicBool>) {
+    let mut signals = STOP_SIGNALS.lock().unwrap();
+    if signals.get(&handle).is_some_and(|s| Arc::ptr_eq(s, stop_signal)) {
+        signals.remove(&handle);
+    }
+}
+
+/// Requests the step to stop. Processors handle the queued records, call the 'shutdown' function of module
+/// and exit. Source scripts are expected to check torustiq.should_stop(). The host is notified once the thread finishes
+#[no_mangle]
+extern "C" fn torustiq_module_common_shutdown(handle: ModuleHandle) {
+    if let Some(s) = STOP_SIGNALS.lock().unwrap().remove(&handle) {
+        s.store(true, Ordering::SeqCst);
+    }
+}
@@ -1,69 +1,741 @@
-use std::collections::HashMap;
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    rc::Rc,
+    sync::{atomic::AtomicBool, Arc, Mutex},
+    time::Duration,
+};
 
-use mlua::{Function, prelude::*};
+use mlua::{Function, HookTriggers, RegistryKey, prelude::*};
 
 use torustiq_common::ffi::{
     shared::get_pipeline_lib_configuration,
     types::module::{ModuleHandle, Record},
 };
 
+use crate::{
+    api::register_torustiq_table,
+    chunks::{compile, ChunkConfig},
+    helpers::lua_to_json,
+    packages::{configure_packages, new_lua, PackagesConfig},
+    state::StateStore,
+};
+
 const LUA_SEND_ERROR: &str = "torustiq_send: failed to load the module configuration";
+/// Name of the step code in Lua error messages
+const SCRIPT_CHUNK_NAME: &str = "=script";
+
+const DEFAULT_TICK_INTERVAL_MS: u64 = 1000;
+
+const DEFAULT_SANDBOX_MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+const DEFAULT_SANDBOX_INSTRUCTION_LIMIT: u64 = 10_000_000;
+/// The instruction budget is checked every N instructions
+const INSTRUCTION_HOOK_INTERVAL: u32 = 1000;
+
+/// Functions which are not available to scripts in sandbox mode: access to files, processes
+/// and loading of arbitrary code. Libraries are removed from package.loaded too, so 'require' can't return them.
+/// The 'debug' library is never loaded, see Lua::new
+const SANDBOX_REMOVED_GLOBALS: &[&str] = &["io", "load", "loadfile", "dofile"];
+const SANDBOX_REMOVED_OS_FUNCTIONS: &[&str] = &["execute", "exit", "getenv", "remove", "rename", "tmpname"];
+
+/// Guardrails for scripts written by pipeline users
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SandboxConfig {
+    pub enabled: bool,
+    /// Memory limit of Lua state in bytes
+    pub memory_limit: usize,
+    /// Max number of Lua instructions executed while processing a single record
+    pub instruction_limit: u64,
+}
+
+impl SandboxConfig {
+    /// Reads the sandbox settings from step parameters:
+    /// - sandbox: 'true' or 'false' (default)
+    /// - sandbox.memory_limit: in bytes, 64 MiB by default
+    /// - sandbox.instruction_limit: per record, 10 000 000 by default
+    pub fn from_step_params(params: &HashMap<String, String>) -> Result<Self, String> {
+        match params.get("sandbox").map(String::as_str) {
+            None | Some("false") => return Ok(SandboxConfig::default()),
+            Some("true") => {},
+            Some(v) => return Err(format!("Invalid value of 'sandbox': '{}'. Expected 'true' or 'false'", v)),
+        };
+
+        let memory_limit = match params.get("sandbox.memory_limit") {
+            Some(v) => v.parse::<usize>()
+                .map_err(|e| format!("Invalid value of 'sandbox.memory_limit': '{}': {}", v, e))?,
+            None => DEFAULT_SANDBOX_MEMORY_LIMIT,
+        };
+        let instruction_limit = match params.get("sandbox.instruction_limit") {
+            Some(v) => v.parse::<u64>()
+                .map_err(|e| format!("Invalid value of 'sandbox.instruction_limit': '{}': {}", v, e))?,
+            None => DEFAULT_SANDBOX_INSTRUCTION_LIMIT,
+        };
+        Ok(SandboxConfig {
+            enabled: true,
+            memory_limit,
+            instruction_limit,
+        })
+    }
+}
+
+/// Settings of Lua environment for a step
+#[derive(Clone, Debug, Default)]
+pub struct LuaEnvConfig {
+    pub module_handle: ModuleHandle,
+    pub params: HashMap<String, String>,
+    pub sandbox: SandboxConfig,
+    pub packages: PackagesConfig,
+    pub chunks: ChunkConfig,
+    /// Shared by all Lua states of the step
+    pub state: Arc<StateStore>,
+    /// Where records sent with torustiq_send and torustiq.send go
+    pub sink: RecordSink,
+    /// Set once the step is requested to stop
+    pub stop_signal: Arc<AtomicBool>,
+    /// How often the 'on_tick' function of module is called
+    pub tick_interval: Duration,
+}
+
+impl LuaEnvConfig {
+    pub fn from_step_params(module_handle: ModuleHandle, params: HashMap<String, String>) -> Result<Self, String> {
+        let tick_interval_ms = match params.get("tick_interval_ms") {
+            Some(v) => v.parse::<u64>()
+                .map_err(|e| format!("Invalid value of 'tick_interval_ms': '{}': {}", v, e))?,
+            None => DEFAULT_TICK_INTERVAL_MS,
+        };
+        if tick_interval_ms == 0 {
+            return Err(String::from("'tick_interval_ms' must be greater than zero"));
+        }
+        let sandbox = SandboxConfig::from_step_params(&params)?;
+        let chunks = ChunkConfig::from_step_params(&params)?;
+        if sandbox.enabled && chunks.allow_bytecode {
+            return Err(String::from("'allow_bytecode' can't be used in sandbox mode: bytecode is not verified by Lua"));
+        }
+        Ok(LuaEnvConfig {
+            module_handle,
+            sandbox,
+            packages: PackagesConfig::from_step_params(&params),
+            chunks,
+            state: Arc::new(StateStore::from_step_params(&params)?),
+            sink: RecordSink::Host,
+            stop_signal: Arc::new(AtomicBool::new(false)),
+            params,
+            tick_interval: Duration::from_millis(tick_interval_ms),
+        })
+    }
+}
+
+/// A record emitted by Lua code
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutputRecord {
+    pub content: Vec<u8>,
+    pub metadata: HashMap<String, String>,
+}
+
+impl OutputRecord {
+    /// Reads a record from a table like {content = "...", metadata = {k = "v"}}. Metadata is optional.
+    /// Content is an arbitrary byte string, metadata values are converted by metadata_from_lua_table
+    pub fn from_lua_table(t: LuaTable<'_>) -> Result<Self, LuaError> {
+        let content: LuaString = t.get("content")?;
+        let metadata = match t.get::<_, Option<LuaTable>>("metadata")? {
+            Some(m) => metadata_from_lua_table(m).map_err(LuaError::RuntimeError)?,
+            None => HashMap::new(),
+        };
+        Ok(OutputRecord {
+            content: content.as_bytes().to_vec(),
+            metadata,
+        })
+    }
+
+    /// Converts a value returned by process function into records:
+    /// - nil: the record is dropped
+    /// - a table with 'content' field: one record
+    /// - an array of such tables: many records
+    ///
+    /// Strings are ignored: scripts which call torustiq_send by themselves used to return an empty string
+    fn vec_from_lua_value(value: LuaValue<'_>) -> Result<Vec<Self>, String> {
+        let t = match value {
+            LuaValue::Nil | LuaValue::String(_) => return Ok(vec![]),
+            LuaValue::Table(t) => t,
+            v => return Err(format!("Unexpected value returned by process function: {}. \
+                Expected nil, a record or an array of records", v.type_name())),
+        };
+        let is_single_record = match t.contains_key("content") {
+            Ok(r) => r,
+            Err(e) => return Err(format!("Failed to read the returned value: {}", e)),
+        };
+        if is_single_record {
+            return OutputRecord::from_lua_table(t).map(|r| vec![r])
+                .map_err(|e| format!("Invalid record returned by process function: {}", e));
+        }
+
+        let mut records = vec![];
+        for (i, item) in t.sequence_values::<LuaTable>().enumerate() {
+            let record = item.and_then(OutputRecord::from_lua_table)
+                .map_err(|e| format!("Invalid record #{} returned by process function: {}", i + 1, e))?;
+            records.push(record);
+        }
+        Ok(records)
+    }
+}
 
-fn torustiq_send(_: &Lua, params: (ModuleHandle, String, HashMap<String, String>)) -> Result<(), LuaError> {
+/// Converts record metadata set by Lua code into strings: numbers and booleans are formatted,
+/// tables are serialized as JSON. Other values are rejected with an error naming the key
+pub fn metadata_from_lua_table(t: LuaTable<'_>) -> Result<HashMap<String, String>, String> {
+    let mut metadata = HashMap::new();
+    for pair in t.pairs::<LuaValue, LuaValue>() {
+        let (k, v) = pair.map_err(|e| format!("Failed to read metadata: {}", e))?;
+        let k = match k {
+            LuaValue::String(s) => match s.to_str() {
+                Ok(s) => String::from(s),
+                Err(_) => return Err(String::from("Metadata key is not valid UTF-8")),
+            },
+            k => return Err(format!("Metadata key must be a string, got {}", k.type_name())),
+        };
+        let v = match v {
+            LuaValue::String(s) => match s.to_str() {
+                Ok(s) => String::from(s),
+                Err(_) => return Err(format!("Metadata field '{}' is not valid UTF-8", k)),
+            },
+            v => match lua_to_json(&v) {
+                Ok(v) => v.to_string(),
+                Err(e) => return Err(format!("Metadata field '{}' can't be converted to string: {}", k, e)),
+            },
+        };
+        metadata.insert(k, v);
+    }
+    Ok(metadata)
+}
+
+/// Passes a record to the next step
+pub fn send_record(module_handle: ModuleHandle, record: OutputRecord) -> Result<(), String> {
     let on_data_receive_cb = match get_pipeline_lib_configuration() {
-        Some(c) => c.on_data_receive_cb.clone(),
-        None => {
-            log::error!("{}", LUA_SEND_ERROR);
-            return Err(LuaError::RuntimeError(String::from(LUA_SEND_ERROR)))
-        },
+        Some(c) => c.on_data_receive_cb,
+        None => return Err(String::from(LUA_SEND_ERROR)),
     };
+    on_data_receive_cb(module_handle, Record::from_std_types(record.content, record.metadata));
+    Ok(())
+}
+
+/// Where records emitted by Lua code go
+#[derive(Clone, Debug, Default)]
+pub enum RecordSink {
+    /// Records are passed to the next step of pipeline
+    #[default]
+    Host,
+    /// Records are collected in memory, e.g. to test scripts without a pipeline
+    Capture(Arc<Mutex<Vec<OutputRecord>>>),
+}
+
+impl RecordSink {
+    pub fn send(&self, module_handle: ModuleHandle, record: OutputRecord) -> Result<(), String> {
+        match self {
+            RecordSink::Host => send_record(module_handle, record),
+            RecordSink::Capture(records) => match records.lock() {
+                Ok(mut r) => {
+                    r.push(record);
+                    Ok(())
+                },
+                Err(e) => Err(format!("Failed to capture a record: {}", e)),
+            },
+        }
+    }
+}
+
+fn torustiq_send(sink: &RecordSink, params: (ModuleHandle, LuaString, Option<LuaTable>)) -> Result<(), LuaError> {
     let (module_handle, content, metadata) = params;
-    let record = Record::from_std_types(content.as_bytes().to_vec(), metadata);
-    on_data_receive_cb(module_handle, record);
+    let record = OutputRecord {
+        content: content.as_bytes().to_vec(),
+        metadata: match metadata {
+            Some(m) => metadata_from_lua_table(m).map_err(LuaError::RuntimeError)?,
+            None => HashMap::new(),
+        },
+    };
+    if let Err(e) = sink.send(module_handle, record) {
+        log::error!("{}", e);
+        return Err(LuaError::RuntimeError(e))
+    }
     Ok(())
 }
 
 /// Lua environment with pre-configured Torustiq functions
 pub struct LuaEnv {
     lua: Lua,
+    sandbox_cfg: SandboxConfig,
+    chunk_cfg: ChunkConfig,
+    /// Instructions left for the current record. None means no limit
+    instruction_budget: Rc<Cell<Option<u64>>>,
+    processor: Option<Processor>,
 }
 
 impl LuaEnv {
-    pub fn try_new() -> Result<Self, String> {        
-        let lua = Lua::new();
-        let fn_torustiq_send = match lua.create_function(torustiq_send) {
+    pub fn try_new(cfg: &LuaEnvConfig) -> Result<Self, String> {
+        let lua = new_lua(&cfg.packages, cfg.sandbox.enabled);
+        let sink = cfg.sink.clone();
+        let fn_torustiq_send = match lua.create_function(move |_, params| torustiq_send(&sink, params)) {
             Ok(f) => f,
             Err(e) => return Err(format!("{}", e)),
         };
         if let Err(e) = lua.globals().set("torustiq_send", fn_torustiq_send) {
             return Err(format!("{}", e));
         }
+        if let Err(e) = register_torustiq_table(&lua, cfg) {
+            return Err(format!("Failed to register the 'torustiq' table: {}", e));
+        }
+        configure_packages(&lua, &cfg.packages, &cfg.chunks, cfg.sandbox.enabled)?;
 
         let lua_env = LuaEnv {
-            lua
+            lua,
+            sandbox_cfg: cfg.sandbox.clone(),
+            chunk_cfg: cfg.chunks.clone(),
+            instruction_budget: Rc::new(Cell::new(None)),
+            processor: None,
         };
-        
+        if cfg.sandbox.enabled {
+            if let Err(e) = lua_env.apply_sandbox() {
+                return Err(format!("Failed to set up the sandbox: {}", e));
+            }
+        }
+
         Ok(lua_env)
     }
 
-    pub fn exec_code<S: Into<String>>(&self, code: S) -> Result<(), String> {
-        match self.lua.load(code.into()).exec() {
+    fn apply_sandbox(&self) -> Result<(), LuaError> {
+        let globals = self.lua.globals();
+        // 'require' returns the loaded libraries before any searcher is called.
+        // The registry table is used as scripts might replace package.loaded
+        let loaded: LuaTable = self.lua.named_registry_value("_LOADED")?;
+        for name in SANDBOX_REMOVED_GLOBALS {
+            globals.set(*name, LuaNil)?;
+            loaded.set(*name, LuaNil)?;
+        }
+        let os: LuaTable = globals.get("os")?;
+        for name in SANDBOX_REMOVED_OS_FUNCTIONS {
+            os.set(*name, LuaNil)?;
+        }
+
+        self.lua.set_memory_limit(self.sandbox_cfg.memory_limit)?;
+
+        let budget = self.instruction_budget.clone();
+        let limit = self.sandbox_cfg.instruction_limit;
+        self.lua.set_hook(HookTriggers::new().every_nth_instruction(INSTRUCTION_HOOK_INTERVAL), move |_, _| {
+            match budget.get() {
+                None => Ok(()),
+                Some(left) if left >= INSTRUCTION_HOOK_INTERVAL as u64 => {
+                    budget.set(Some(left - INSTRUCTION_HOOK_INTERVAL as u64));
+                    Ok(())
+                },
+                Some(_) => Err(LuaError::RuntimeError(format!("The limit of {} instructions per record is exceeded", limit))),
+            }
+        });
+        Ok(())
+    }
+
+    /// Runs a function with the instruction budget of one record, if the sandbox is enabled
+    fn call_with_limits<R, F: FnOnce() -> R>(&self, f: F) -> R {
+        if self.sandbox_cfg.enabled {
+            self.instruction_budget.set(Some(self.sandbox_cfg.instruction_limit));
+        }
+        let result = f();
+        self.instruction_budget.set(None);
+        result
+    }
+
+    /// Runs the code. It's either Lua source or bytecode, see chunks::compile
+    pub fn exec_code<C: AsRef<[u8]>>(&self, code: C) -> Result<(), String> {
+        let func = compile(&self.lua, &self.chunk_cfg, SCRIPT_CHUNK_NAME, code.as_ref(), false)?;
+        match func.call::<_, ()>(()) {
             Ok(_) => Ok(()),
             Err(e) => Err(format!("An error occurred in Lua code: {}", e)),
         }
     }
 
-    pub fn create_function_from_code<S: Into<String>>(&self, code: S) -> Result<Function<'_>, String> {
-        match self.lua.load(code.into()).eval() {
-            Ok(f) => Ok(f),
-            Err(e) => Err(format!("Failed to create a function from Lua code: {}", e)),
+    /// Evaluates the code of processor: either a function or a module table, see Processor.
+    /// A Lua env serves one processor
+    pub fn load_processor<C: AsRef<[u8]>>(&mut self, code: C) -> Result<(), String> {
+        let func = compile(&self.lua, &self.chunk_cfg, SCRIPT_CHUNK_NAME, code.as_ref(), true)?;
+        let processor = match func.call::<_, LuaValue>(()) {
+            Ok(LuaValue::Function(f)) => self.lua.create_registry_value(f).map(Processor::Function)
+                .map_err(|e| format!("Failed to store the processor function: {}", e))?,
+            Ok(LuaValue::Table(t)) => Processor::Module(ScriptModule::from_lua_table(&self.lua, t)?),
+            Ok(v) => return Err(format!("Lua code must evaluate to a function or a module table, got {}", v.type_name())),
+            Err(e) => return Err(format!("Failed to create a processor from Lua code: {}", e)),
+        };
+        self.processor = Some(processor);
+        Ok(())
+    }
+
+    fn get_function(&self, key: &RegistryKey) -> Result<Function<'_>, String> {
+        self.lua.registry_value(key).map_err(|e| format!("Failed to load a function of processor: {}", e))
+    }
+
+    /// Calls the 'init' function of module with step params
+    pub fn init_processor(&self, params: &HashMap<String, String>) -> Result<(), String> {
+        let init = match &self.processor {
+            Some(Processor::Module(ScriptModule { init: Some(k), .. })) => self.get_function(k)?,
+            _ => return Ok(()),
+        };
+        match self.call_with_limits(|| init.call::<_, ()>(params.clone())) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Module init failure: {}", e)),
         }
     }
-    
-    pub fn call_process_record_function(&self, func: &Function<'_>, module_handle: ModuleHandle, record: Record) -> Result<(), String> {
-        match func.call::<_, String>((module_handle, record.content.to_string(), record.get_metadata_as_hashmap())) {
-            Ok(_) => Ok(()), // the function needs to return something, so we return a string which is never handled
+
+    /// Calls the processor and returns records emitted by it.
+    /// Records sent with torustiq_send are passed to the sink immediately and are not returned.
+    /// Content is passed to Lua as a byte string, so binary payloads are not altered
+    pub fn call_processor(&self, module_handle: ModuleHandle, content: &[u8],
+        metadata: HashMap<String, String>) -> Result<Vec<OutputRecord>, String> {
+        let result = match &self.processor {
+            Some(Processor::Function(k)) => {
+                let f = self.get_function(k)?;
+                let content = match self.lua.create_string(content) {
+                    Ok(c) => c,
+                    Err(e) => return Err(format!("Failed to pass the record content to Lua: {}", e)),
+                };
+                self.call_with_limits(|| f.call::<_, LuaValue>((module_handle, content, metadata)))
+            },
+            Some(Processor::Module(ScriptModule { process: Some(k), .. })) => {
+                let f = self.get_function(k)?;
+                let record = self.create_record_table(content, metadata)?;
+                self.call_with_limits(|| f.call::<_, LuaValue>(record))
+            },
+            Some(Processor::Module(_)) => return Err(String::from("Module has no 'process' function")),
+            None => return Err(String::from("Lua processor is not loaded")),
+        };
+        match result {
+            Ok(v) => OutputRecord::vec_from_lua_value(v),
             Err(e) => Err(format!("Function call failure: {}", e))
         }
     }
-}
\ No newline at end of file
+
+    /// Calls the 'process_batch' function of module with an array of records.
+    /// The function returns any number of records, in the same way as 'process'
+    pub fn call_batch_processor(&self, records: &[OutputRecord]) -> Result<Vec<OutputRecord>, String> {
+        let f = match &self.processor {
+            Some(Processor::Module(ScriptModule { process_batch: Some(k), .. })) => self.get_function(k)?,
+            _ => return Err(String::from("Module has no 'process_batch' function")),
+        };
+        let batch = match self.lua.create_table_with_capacity(records.len(), 0) {
+            Ok(t) => t,
+            Err(e) => return Err(format!("Failed to pass the batch to Lua: {}", e)),
+        };
+        for (i, record) in records.iter().enumerate() {
+            let t = self.create_record_table(&record.content, record.metadata.clone())?;
+            if let Err(e) = batch.raw_set(i + 1, t) {
+                return Err(format!("Failed to pass the batch to Lua: {}", e));
+            }
+        }
+        match self.call_with_limits(|| f.call::<_, LuaValue>(batch)) {
+            Ok(v) => OutputRecord::vec_from_lua_value(v),
+            Err(e) => Err(format!("Batch function call failure: {}", e)),
+        }
+    }
+
+    /// Returns true if the processor is a module with 'process_batch' function
+    pub fn supports_batches(&self) -> bool {
+        matches!(&self.processor, Some(Processor::Module(ScriptModule { process_batch: Some(_), .. })))
+    }
+
+    /// Returns true if the processor handles records one by one
+    pub fn supports_single_records(&self) -> bool {
+        matches!(&self.processor, Some(Processor::Function(_)) | Some(Processor::Module(ScriptModule { process: Some(_), .. })))
+    }
+
+    /// Creates a {content = ..., metadata = ...} table passed to module functions
+    fn create_record_table(&self, content: &[u8], metadata: HashMap<String, String>) -> Result<LuaTable<'_>, String> {
+        let content = match self.lua.create_string(content) {
+            Ok(c) => c,
+            Err(e) => return Err(format!("Failed to pass the record content to Lua: {}", e)),
+        };
+        match self.lua.create_table_from([("content", LuaValue::String(content))])
+            .and_then(|t| t.set("metadata", metadata).map(|_| t)) {
+            Ok(t) => Ok(t),
+            Err(e) => Err(format!("Failed to pass the record to Lua: {}", e)),
+        }
+    }
+
+    /// Calls the 'on_tick' function of module. It returns records in the same way as 'process'
+    pub fn tick_processor(&self) -> Result<Vec<OutputRecord>, String> {
+        match &self.processor {
+            Some(Processor::Module(ScriptModule { on_tick: Some(k), .. })) => self.call_module_hook(k, "on_tick"),
+            _ => Ok(vec![]),
+        }
+    }
+
+    /// Calls the 'shutdown' function of module. It can return the last records, e.g. to flush the aggregated data
+    pub fn shutdown_processor(&self) -> Result<Vec<OutputRecord>, String> {
+        match &self.processor {
+            Some(Processor::Module(ScriptModule { shutdown: Some(k), .. })) => self.call_module_hook(k, "shutdown"),
+            _ => Ok(vec![]),
+        }
+    }
+
+    fn call_module_hook(&self, key: &RegistryKey, name: &str) -> Result<Vec<OutputRecord>, String> {
+        let func = self.get_function(key)?;
+        match self.call_with_limits(|| func.call::<_, LuaValue>(())) {
+            Ok(v) => OutputRecord::vec_from_lua_value(v),
+            Err(e) => Err(format!("Module {} failure: {}", name, e)),
+        }
+    }
+}
+
+/// Code of transformation or destination step. Functions are kept in the registry of Lua state
+enum Processor {
+    /// A function(module_handle, content, metadata) called for each record
+    Function(RegistryKey),
+    /// A table with lifecycle functions
+    Module(ScriptModule),
+}
+
+/// A script which returns a table with functions:
+/// - init(params): optional, called once before processing
+/// - process(record): called for each {content = ..., metadata = ...} record
+/// - process_batch(records): called with an array of records if 'batch_size' step param is set.
+///   At least one of 'process' and 'process_batch' is required
+/// - on_tick(): optional, called periodically, see 'tick_interval_ms' step param
+/// - shutdown(): optional, called once the step is stopped
+struct ScriptModule {
+    init: Option<RegistryKey>,
+    process: Option<RegistryKey>,
+    process_batch: Option<RegistryKey>,
+    on_tick: Option<RegistryKey>,
+    shutdown: Option<RegistryKey>,
+}
+
+impl ScriptModule {
+    fn from_lua_table(lua: &Lua, t: LuaTable<'_>) -> Result<Self, String> {
+        let get_fn = |name: &str| -> Result<Option<RegistryKey>, String> {
+            match t.get::<_, Option<Function>>(name) {
+                Ok(Some(f)) => lua.create_registry_value(f).map(Some)
+                    .map_err(|e| format!("Failed to store the '{}' function: {}", name, e)),
+                Ok(None) => Ok(None),
+                Err(e) => Err(format!("Invalid '{}' field of module table: {}", name, e)),
+            }
+        };
+        let process = get_fn("process")?;
+        let process_batch = get_fn("process_batch")?;
+        if process.is_none() && process_batch.is_none() {
+            return Err(String::from("Module table has neither 'process' nor 'process_batch' function"));
+        }
+        Ok(ScriptModule {
+            init: get_fn("init")?,
+            process,
+            process_batch,
+            on_tick: get_fn("on_tick")?,
+            shutdown: get_fn("shutdown")?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use mlua::Function;
+
+    use super::{LuaEnv, LuaEnvConfig, OutputRecord, Processor, SandboxConfig};
+
+    fn new_sandboxed_env_config(memory_limit: usize, instruction_limit: u64) -> LuaEnvConfig {
+        LuaEnvConfig {
+            sandbox: SandboxConfig {
+                enabled: true,
+                memory_limit,
+                instruction_limit,
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_sandbox_config_from_step_params() {
+        assert_eq!(SandboxConfig::from_step_params(&HashMap::new()), Ok(SandboxConfig::default()));
+        let params = HashMap::from([
+            (String::from("sandbox"), String::from("true")),
+            (String::from("sandbox.instruction_limit"), String::from("5000")),
+        ]);
+        assert_eq!(SandboxConfig::from_step_params(&params), Ok(new_sandboxed_env_config(64 * 1024 * 1024, 5000).sandbox));
+        assert!(SandboxConfig::from_step_params(&HashMap::from([
+            (String::from("sandbox"), String::from("yes")),
+        ])).is_err());
+    }
+
+    #[test]
+    fn test_sandbox_removes_unsafe_functions() {
+        let lua = LuaEnv::try_new(&new_sandboxed_env_config(1024 * 1024, 1000)).unwrap();
+        lua.exec_code("assert(io == nil and load == nil and dofile == nil and os.execute == nil)").unwrap();
+        lua.exec_code("assert(os.time() ~= nil and string.upper('a') == 'A')").unwrap();
+        // Removed libraries can't be loaded with 'require' either
+        let err = lua.exec_code(r#"require("io").popen("echo escaped")"#).unwrap_err();
+        assert!(err.contains("module 'io' not found"), "Unexpected error: {}", err);
+        lua.exec_code(r#"assert(package.loaded.io == nil and require("os").execute == nil)"#).unwrap();
+
+        let lua = LuaEnv::try_new(&LuaEnvConfig::default()).unwrap();
+        lua.exec_code("assert(io ~= nil and os.execute ~= nil)").unwrap();
+    }
+
+    #[test]
+    fn test_sandbox_instruction_limit() {
+        let lua = LuaEnv::try_new(&new_sandboxed_env_config(1024 * 1024, 100_000)).unwrap();
+        let func: Function = lua.lua.load("function (n) for i = 1, n do end return 'done' end").eval().unwrap();
+
+        assert_eq!(lua.call_with_limits(|| func.call::<_, String>(100)).unwrap(), "done");
+        let err = lua.call_with_limits(|| func.call::<_, String>(1_000_000_000)).unwrap_err();
+        assert!(err.to_string().contains("instructions per record"), "Unexpected error: {}", err);
+        // The budget is reset for every record
+        assert_eq!(lua.call_with_limits(|| func.call::<_, String>(100)).unwrap(), "done");
+        // Code outside of record processing is not limited
+        lua.exec_code("for i = 1, 1000000 do end").unwrap();
+    }
+
+    #[test]
+    fn test_sandbox_memory_limit() {
+        let lua = LuaEnv::try_new(&new_sandboxed_env_config(1024 * 1024, 1_000_000_000)).unwrap();
+        let err = lua.exec_code("local s = string.rep('x', 16 * 1024 * 1024)").unwrap_err();
+        assert!(err.contains("memory"), "Unexpected error: {}", err);
+    }
+
+    #[test]
+    fn test_process_function_return_values() {
+        let mut lua = LuaEnv::try_new(&LuaEnvConfig::default()).unwrap();
+        lua.load_processor(r#"function (handle, content, metadata)
+            if content == "drop" then
+                return nil
+            elseif content == "one" then
+                return {content = content .. "!", metadata = {a = metadata.a}}
+            elseif content == "many" then
+                return {{content = "1"}, {content = "2", metadata = {b = "c"}}}
+            elseif content == "legacy" then
+                return ""
+            end
+            return 42
+        end"#).unwrap();
+        let metadata = HashMap::from([(String::from("a"), String::from("b"))]);
+        let call = |content: &str| lua.call_processor(1, content.as_bytes(), metadata.clone());
+
+        assert_eq!(call("drop"), Ok(vec![]));
+        assert_eq!(call("legacy"), Ok(vec![]));
+        assert_eq!(call("one"), Ok(vec![OutputRecord {
+            content: b"one!".to_vec(),
+            metadata: metadata.clone(),
+        }]));
+        assert_eq!(call("many"), Ok(vec![
+            OutputRecord {
+                content: b"1".to_vec(),
+                metadata: HashMap::new(),
+            },
+            OutputRecord {
+                content: b"2".to_vec(),
+                metadata: HashMap::from([(String::from("b"), String::from("c"))]),
+            },
+        ]));
+        assert!(call("number").is_err());
+    }
+
+    #[test]
+    fn test_typed_metadata() {
+        let mut lua = LuaEnv::try_new(&LuaEnvConfig::default()).unwrap();
+        lua.load_processor(r#"function (handle, content, metadata)
+            if content == "invalid" then
+                return {content = content, metadata = {callback = print}}
+            end
+            metadata.count = 5
+            metadata.ratio = 0.5
+            metadata.valid = true
+            metadata.tags = {"a", "b"}
+            return {content = content, metadata = metadata}
+        end"#).unwrap();
+        let records = lua.call_processor(1, b"ok", HashMap::from([(String::from("k"), String::from("v"))])).unwrap();
+        assert_eq!(records[0].metadata, HashMap::from([
+            (String::from("k"), String::from("v")),
+            (String::from("count"), String::from("5")),
+            (String::from("ratio"), String::from("0.5")),
+            (String::from("valid"), String::from("true")),
+            (String::from("tags"), String::from(r#"["a","b"]"#)),
+        ]));
+
+        let err = lua.call_processor(1, b"invalid", HashMap::new()).unwrap_err();
+        assert!(err.contains("'callback'"), "Unexpected error: {}", err);
+    }
+
+    #[test]
+    fn test_process_function_binary_content() {
+        let mut lua = LuaEnv::try_new(&LuaEnvConfig::default()).unwrap();
+        lua.load_processor(r#"function (handle, content, metadata)
+            return {content = content .. "\x00\xff"}
+        end"#).unwrap();
+        let content: &[u8] = &[0x08, 0x96, 0x01, 0xc3, 0x28];
+        let records = lua.call_processor(1, content, HashMap::new()).unwrap();
+        assert_eq!(records[0].content, [0x08, 0x96, 0x01, 0xc3, 0x28, 0x00, 0xff]);
+    }
+
+    #[test]
+    fn test_script_module() {
+        let mut lua = LuaEnv::try_new(&LuaEnvConfig::default()).unwrap();
+        lua.load_processor(r#"
+            local counts = {}
+            local prefix
+            return {
+                init = function (params)
+                    prefix = params.prefix
+                end,
+                process = function (record)
+                    local k = record.metadata.key
+                    counts[k] = (counts[k] or 0) + 1
+                    return nil
+                end,
+                on_tick = function ()
+                    local out = {}
+                    for k, n in pairs(counts) do
+                        table.insert(out, {content = prefix .. k .. "=" .. n})
+                    end
+                    counts = {}
+                    return out
+                end,
+            }
+        "#).unwrap();
+        assert!(matches!(lua.processor, Some(Processor::Module(_))));
+
+        lua.init_processor(&HashMap::from([(String::from("prefix"), String::from("count:"))])).unwrap();
+        let metadata = HashMap::from([(String::from("key"), String::from("a"))]);
+        assert_eq!(lua.call_processor(1, b"1", metadata.clone()), Ok(vec![]));
+        assert_eq!(lua.call_processor(1, b"2", metadata.clone()), Ok(vec![]));
+        assert_eq!(lua.tick_processor(), Ok(vec![OutputRecord {
+            content: b"count:a=2".to_vec(),
+            metadata: HashMap::new(),
+        }]));
+        assert_eq!(lua.tick_processor(), Ok(vec![]));
+        // No shutdown function
+        assert_eq!(lua.shutdown_processor(), Ok(vec![]));
+
+        assert!(lua.load_processor("return {init = function () end}").is_err());
+        assert!(lua.load_processor("return 1").is_err());
+    }
+
+    #[test]
+    fn test_script_module_batch() {
+        let mut lua = LuaEnv::try_new(&LuaEnvConfig::default()).unwrap();
+        lua.load_processor(r#"
+            return {
+                process_batch = function (records)
+                    local keys = {}
+                    for _, r in ipairs(records) do
+                        table.insert(keys, r.metadata.key .. ":" .. r.content)
+                    end
+                    return {content = table.concat(keys, ",")}
+                end,
+            }
+        "#).unwrap();
+        assert!(lua.supports_batches() && !lua.supports_single_records());
+
+        let record = |content: &str, key: &str| OutputRecord {
+            content: content.as_bytes().to_vec(),
+            metadata: HashMap::from([(String::from("key"), String::from(key))]),
+        };
+        assert_eq!(lua.call_batch_processor(&[record("1", "a"), record("2", "b")]), Ok(vec![OutputRecord {
+            content: b"a:1,b:2".to_vec(),
+            metadata: HashMap::new(),
+        }]));
+        assert!(lua.call_processor(1, b"1", HashMap::new()).is_err());
+    }
+}
@@ -0,0 +1,225 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use mlua::{prelude::*, Function};
+
+use crate::chunks::{compile, ChunkConfig};
+
+/// Where scripts look for modules loaded with 'require'
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PackagesConfig {
+    /// Templates prepended to package.path, e.g. '/opt/lua/?.lua;/opt/lua/?/init.lua'
+    pub lua_path: Option<String>,
+    /// Templates prepended to package.cpath. Enables loading of native modules
+    pub lua_cpath: Option<String>,
+    /// A directory with modules which are compiled at start and registered in package.preload
+    pub preload_dir: Option<String>,
+}
+
+impl PackagesConfig {
+    /// Reads the module search settings from step parameters:
+    /// - lua_path: templates for package.path
+    /// - lua_cpath: templates for package.cpath
+    /// - lua_preload_dir: a directory with modules
+    pub fn from_step_params(params: &HashMap<String, String>) -> Self {
+        PackagesConfig {
+            lua_path: params.get("lua_path").cloned(),
+            lua_cpath: params.get("lua_cpath").cloned(),
+            preload_dir: params.get("lua_preload_dir").cloned(),
+        }
+    }
+}
+
+/// Creates a Lua state. Native modules need the C searchers and package.loadlib, which are disabled in safe mode,
+/// so the state is created in unsafe mode if 'lua_cpath' is set outside of sandbox
+pub fn new_lua(cfg: &PackagesConfig, sandbox: bool) -> Lua {
+    match (&cfg.lua_cpath, sandbox) {
+        // SAFETY: the debug library is still not loaded. Native modules are trusted like the step code itself
+        (Some(_), false) => unsafe { Lua::unsafe_new_with(LuaStdLib::ALL_SAFE, LuaOptions::default()) },
+        _ => Lua::new(),
+    }
+}
+
+/// Sets up the 'package' table. In sandbox mode native modules are not allowed and Lua modules are only loaded
+/// from package.preload and 'lua_path'
+pub fn configure_packages(lua: &Lua, cfg: &PackagesConfig, chunk_cfg: &ChunkConfig, sandbox: bool) -> Result<(), String> {
+    let package: LuaTable = lua.globals().get("package").map_err(|e| format!("Failed to load the 'package' table: {}", e))?;
+    let prepend = |field: &str, templates: &str| -> Result<(), String> {
+        let current: String = package.get(field).map_err(|e| e.to_string())?;
+        package.set(field, format!("{};{}", templates.trim_end_matches(';'), current)).map_err(|e| e.to_string())
+    };
+
+    if let Some(p) = &cfg.lua_path {
+        prepend("path", p).map_err(|e| format!("Failed to set package.path: {}", e))?;
+    }
+    if sandbox {
+        if cfg.lua_cpath.is_some() {
+            return Err(String::from("'lua_cpath' can't be used in sandbox mode: native modules are not allowed"));
+        }
+        package.set("cpath", "").and_then(|_| package.set("loadlib", LuaNil))
+            .map_err(|e| format!("Failed to disable native modules: {}", e))?;
+        restrict_searchers(lua, &package, cfg, chunk_cfg)
+            .map_err(|e| format!("Failed to restrict the module searchers: {}", e))?;
+    } else if let Some(p) = &cfg.lua_cpath {
+        prepend("cpath", p).map_err(|e| format!("Failed to set package.cpath: {}", e))?;
+    }
+
+    if let Some(dir) = &cfg.preload_dir {
+        let preload: LuaTable = package.get("preload").map_err(|e| format!("Failed to load package.preload: {}", e))?;
+        preload_modules(lua, chunk_cfg, &preload, Path::new(dir), "")?;
+    }
+    Ok(())
+}
+
+/// Replaces the standard searchers with the preload searcher and a searcher of Lua files in 'lua_path'.
+/// The templates are captured here, so scripts can't widen the search by changing package.path.
+/// Files are compiled with the chunk settings, i.e. bytecode is rejected like in the step code
+fn restrict_searchers(lua: &Lua, package: &LuaTable, cfg: &PackagesConfig, chunk_cfg: &ChunkConfig) -> Result<(), LuaError> {
+    let searchers: LuaTable = package.get("searchers")?;
+    let preload_searcher: Function = searchers.get(1)?;
+    let restricted = lua.create_table()?;
+    restricted.push(preload_searcher)?;
+
+    let path = cfg.lua_path.clone().unwrap_or_default();
+    package.set("path", path.clone())?;
+    let templates: Vec<String> = path.split(';').filter(|t| !t.is_empty()).map(String::from).collect();
+    if !templates.is_empty() {
+        let chunk_cfg = chunk_cfg.clone();
+        restricted.push(lua.create_function(move |lua, name: String| {
+            let mut not_found = String::new();
+            for template in &templates {
+                let file_name = template.replace('?', &name.replace('.', "/"));
+                let code = match fs::read(&file_name) {
+                    Ok(c) => c,
+                    Err(_) => {
+                        not_found.push_str(&format!("\n\tno file '{}'", file_name));
+                        continue;
+                    },
+                };
+                let loader = compile(lua, &chunk_cfg, &format!("@{}", file_name), &code, false)
+                    .map_err(|e| LuaError::RuntimeError(format!("Failed to load the module '{}' from '{}': {}", name, file_name, e)))?;
+                return Ok((LuaValue::Function(loader), Some(file_name)));
+            }
+            Ok((LuaValue::String(lua.create_string(&not_found)?), None))
+        })?)?;
+    }
+    package.set("searchers", restricted)
+}
+
+/// Registers each *.lua file in directory as a module. Subdirectories are mapped to dotted names,
+/// e.g. 'parsers/csv.lua' is loaded with require("parsers.csv")
+fn preload_modules(lua: &Lua, chunk_cfg: &ChunkConfig, preload: &LuaTable<'_>, dir: &Path, prefix: &str) -> Result<(), String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) => return Err(format!("Failed to read the directory of preloaded modules '{}': {}", dir.display(), e)),
+    };
+    for entry in entries {
+        let path = match entry {
+            Ok(e) => e.path(),
+            Err(e) => return Err(format!("Failed to read the directory of preloaded modules '{}': {}", dir.display(), e)),
+        };
+        let name = match path.file_stem().and_then(|s| s.to_str()) {
+            Some(n) => format!("{}{}", prefix, n),
+            None => continue,
+        };
+        if path.is_dir() {
+            preload_modules(lua, chunk_cfg, preload, &path, &format!("{}.", name))?;
+            continue;
+        }
+        if path.extension().and_then(|e| e.to_str()) != Some("lua") {
+            continue;
+        }
+
+        let code = fs::read(&path)
+            .map_err(|e| format!("Failed to read the module '{}': {}", path.display(), e))?;
+        let loader = compile(lua, chunk_cfg, &format!("@{}", path.display()), &code, false)
+            .map_err(|e| format!("Failed to compile the module '{}': {}", path.display(), e))?;
+        preload.set(name, loader).map_err(|e| format!("Failed to register the module '{}': {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{lua_env::{LuaEnv, LuaEnvConfig, SandboxConfig}, test_utils::TempDir};
+
+    use super::PackagesConfig;
+
+    #[test]
+    fn test_lua_path_and_preload() {
+        let root = TempDir::new("packages");
+        fs::create_dir_all(root.join("lib")).unwrap();
+        fs::create_dir_all(root.join("preload/parsers")).unwrap();
+        fs::write(root.join("lib/greeting.lua"), "return {hello = function (n) return 'hello, ' .. n end}").unwrap();
+        fs::write(root.join("preload/parsers/kv.lua"), "return {sep = '='}").unwrap();
+        fs::write(root.join("preload/README.md"), "not a module").unwrap();
+
+        let cfg = LuaEnvConfig {
+            packages: PackagesConfig {
+                lua_path: Some(format!("{}/?.lua", root.join("lib").display())),
+                lua_cpath: None,
+                preload_dir: Some(root.join("preload").display().to_string()),
+            },
+            ..Default::default()
+        };
+        let lua = LuaEnv::try_new(&cfg).unwrap();
+        lua.exec_code(r#"
+            assert(require("greeting").hello("lua") == "hello, lua")
+            assert(require("parsers.kv").sep == "=")
+        "#).unwrap();
+
+        let sandboxed_cfg = LuaEnvConfig {
+            sandbox: SandboxConfig {
+                enabled: true,
+                memory_limit: 1024 * 1024,
+                instruction_limit: 1000,
+            },
+            ..cfg.clone()
+        };
+        let lua = LuaEnv::try_new(&sandboxed_cfg).unwrap();
+        lua.exec_code(r#"
+            assert(require("parsers.kv").sep == "=")
+            assert(require("greeting").hello("sandbox") == "hello, sandbox")
+            assert(package.cpath == "" and package.loadlib == nil)
+        "#).unwrap();
+
+        // Files outside of 'lua_path' can't be loaded, even if package.path is changed by the script
+        fs::create_dir_all(root.join("outside")).unwrap();
+        fs::write(root.join("outside/secret.lua"), "return {}").unwrap();
+        let err = lua.exec_code(format!(r#"
+            package.path = "{}/?.lua;" .. package.path
+            require("secret")
+        "#, root.join("outside").display())).unwrap_err();
+        assert!(err.contains("module 'secret' not found"), "Unexpected error: {}", err);
+
+        let mut invalid_cfg = sandboxed_cfg.clone();
+        invalid_cfg.packages.lua_cpath = Some(String::from("/opt/lua/?.so"));
+        assert!(LuaEnv::try_new(&invalid_cfg).is_err());
+    }
+
+    #[test]
+    fn test_lua_cpath() {
+        let root = TempDir::new("cpath");
+        fs::write(root.join("native.so"), "not a shared library").unwrap();
+        let cfg = |lua_cpath: Option<String>| LuaEnvConfig {
+            packages: PackagesConfig {
+                lua_path: None,
+                lua_cpath,
+                preload_dir: None,
+            },
+            ..Default::default()
+        };
+
+        // Native modules are not searched for in safe mode
+        let lua = LuaEnv::try_new(&cfg(None)).unwrap();
+        let err = lua.exec_code(r#"require("native")"#).unwrap_err();
+        assert!(err.contains("module 'native' not found"), "Unexpected error: {}", err);
+
+        // The file is found through package.cpath and passed to the dynamic loader
+        let lua = LuaEnv::try_new(&cfg(Some(format!("{}/?.so", root.path().display())))).unwrap();
+        let err = lua.exec_code(r#"require("native")"#).unwrap_err();
+        assert!(err.contains("error loading module 'native'"), "Unexpected error: {}", err);
+        assert!(err.contains(&root.join("native.so").display().to_string()), "Unexpected error: {}", err);
+    }
+}
@@ -0,0 +1,285 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex, MutexGuard, PoisonError},
+    thread,
+    time::Duration,
+};
+
+use log::error;
+use mlua::prelude::*;
+use serde_json::Value;
+
+use crate::helpers::{json_to_lua, lua_to_json};
+
+const DEFAULT_FLUSH_INTERVAL_MS: u64 = 1000;
+
+/// Key-value state of a step shared by all its Lua states, including the reloaded ones.
+/// Values are kept in memory and written to a JSON file at 'state_path' on flush
+#[derive(Debug, Default)]
+pub struct StateStore {
+    path: Option<PathBuf>,
+    values: Mutex<HashMap<String, Value>>,
+    /// Set if values are changed since the last flush
+    changed: AtomicBool,
+    /// Serializes writes of the state file
+    flush_lock: Mutex<()>,
+    /// How often the changed state is written by the flusher thread, see start_flusher
+    flush_interval: Duration,
+}
+
+impl StateStore {
+    /// Reads the state settings from step parameters:
+    /// - state_path: a file to keep the state in. If not set, the state is lost once the step is stopped
+    /// - state_flush_interval_ms: how often the changed state is written to the file, 1000 by default
+    pub fn from_step_params(params: &HashMap<String, String>) -> Result<Self, String> {
+        let path = match params.get("state_path") {
+            Some(p) => PathBuf::from(p),
+            None => return Ok(StateStore::default()),
+        };
+        let flush_interval_ms = match params.get("state_flush_interval_ms") {
+            Some(v) => v.parse::<u64>()
+                .map_err(|e| format!("Invalid value of 'state_flush_interval_ms': '{}': {}", v, e))?,
+            None => DEFAULT_FLUSH_INTERVAL_MS,
+        };
+        if flush_interval_ms == 0 {
+            return Err(String::from("'state_flush_interval_ms' must be greater than zero"));
+        }
+        Ok(StateStore {
+            flush_interval: Duration::from_millis(flush_interval_ms),
+            ..StateStore::open(path)?
+        })
+    }
+
+    /// Loads the state from a file. The file is created on the first flush if it doesn't exist
+    pub fn open(path: PathBuf) -> Result<Self, String> {
+        let values = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|e| format!("Failed to parse the state file '{}': {}", path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(format!("Failed to read the state file '{}': {}", path.display(), e)),
+        };
+        Ok(StateStore {
+            path: Some(path),
+            values: Mutex::new(values),
+            flush_interval: Duration::from_millis(DEFAULT_FLUSH_INTERVAL_MS),
+            ..Default::default()
+        })
+    }
+
+    fn values(&self) -> MutexGuard<'_, HashMap<String, Value>> {
+        self.values.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn get(&self, key: &str) -> Option<Value> {
+        self.values().get(key).cloned()
+    }
+
+    pub fn set(&self, key: &str, value: Value) {
+        self.values().insert(String::from(key), value);
+        self.changed.store(true, Ordering::SeqCst);
+    }
+
+    pub fn delete(&self, key: &str) -> Option<Value> {
+        let value = self.values().remove(key);
+        self.changed.store(true, Ordering::SeqCst);
+        value
+    }
+
+    /// Adds delta to an integer value. A missing value is treated as zero
+    pub fn incr(&self, key: &str, delta: i64) -> Result<i64, String> {
+        let mut values = self.values();
+        let current = match values.get(key) {
+            None => 0,
+            Some(v) => match v.as_i64() {
+                Some(i) => i,
+                None => return Err(format!("State value '{}' is not an integer: {}", key, v)),
+            },
+        };
+        let new_value = match current.checked_add(delta) {
+            Some(v) => v,
+            None => return Err(format!("State value '{}' overflows", key)),
+        };
+        values.insert(String::from(key), Value::from(new_value));
+        self.changed.store(true, Ordering::SeqCst);
+        Ok(new_value)
+    }
+
+    /// Writes the state to the file, if it's configured. The file is replaced atomically
+    pub fn flush(&self) -> Result<(), String> {
+        let path = match &self.path {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        let _flush_guard = self.flush_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        let data = {
+            let values = self.values();
+            self.changed.store(false, Ordering::SeqCst);
+            serde_json::to_vec(&*values)
+        };
+        let data = match data {
+            Ok(d) => d,
+            Err(e) => {
+                self.changed.store(true, Ordering::SeqCst);
+                return Err(format!("Failed to serialize the state: {}", e))
+            },
+        };
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let result = fs::write(&tmp_path, data)
+            .and_then(|_| fs::rename(&tmp_path, path))
+            .map_err(|e| format!("Failed to write the state file '{}': {}", path.display(), e));
+        if result.is_err() {
+            self.changed.store(true, Ordering::SeqCst);
+        }
+        result
+    }
+}
+
+/// Writes the changed state every 'flush_interval' in a background thread, so a crash or kill of the host loses
+/// only the changes of the last interval. The thread exits once the store is dropped
+pub fn start_flusher(store: &Arc<StateStore>) {
+    if store.path.is_none() {
+        return;
+    }
+    let interval = store.flush_interval;
+    let store = Arc::downgrade(store);
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let store = match store.upgrade() {
+            Some(s) => s,
+            None => return,
+        };
+        if store.changed.load(Ordering::SeqCst) {
+            if let Err(e) = store.flush() {
+                error!("Failed to save the state: {}", e);
+            }
+        }
+    });
+}
+
+/// Creates the 'torustiq.state' table:
+/// - state.get(key): returns the value or nil
+/// - state.set(key, value): value is anything representable in JSON
+/// - state.delete(key)
+/// - state.incr(key[, delta]): adds delta (1 by default) to an integer value and returns the result
+pub fn create_state_table(lua: &Lua, store: Arc<StateStore>) -> Result<LuaTable<'_>, LuaError> {
+    let state = lua.create_table()?;
+
+    let get_store = store.clone();
+    state.set("get", lua.create_function(move |lua, key: String| {
+        match get_store.get(&key) {
+            Some(v) => json_to_lua(lua, &v),
+            None => Ok(LuaNil),
+        }
+    })?)?;
+
+    let set_store = store.clone();
+    state.set("set", lua.create_function(move |_, (key, value): (String, LuaValue)| {
+        if value.is_nil() {
+            set_store.delete(&key);
+            return Ok(())
+        }
+        match lua_to_json(&value) {
+            Ok(v) => set_store.set(&key, v),
+            Err(e) => return Err(LuaError::RuntimeError(format!("Failed to store the state value '{}': {}", key, e))),
+        };
+        Ok(())
+    })?)?;
+
+    let delete_store = store.clone();
+    state.set("delete", lua.create_function(move |_, key: String| {
+        delete_store.delete(&key);
+        Ok(())
+    })?)?;
+
+    state.set("incr", lua.create_function(move |_, (key, delta): (String, Option<i64>)| {
+        store.incr(&key, delta.unwrap_or(1)).map_err(LuaError::RuntimeError)
+    })?)?;
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs, sync::Arc, thread, time::Duration};
+
+    use serde_json::Value;
+
+    use crate::{lua_env::{LuaEnv, LuaEnvConfig}, test_utils::{to_hashmap, TempDir}};
+
+    use super::{start_flusher, StateStore};
+
+    #[test]
+    fn test_state() {
+        let dir = TempDir::new("state");
+        let path = dir.join("state.json");
+
+        let cfg = LuaEnvConfig {
+            state: Arc::new(StateStore::open(path.clone()).unwrap()),
+            ..Default::default()
+        };
+        let lua = LuaEnv::try_new(&cfg).unwrap();
+        lua.exec_code(r#"
+            assert(torustiq.state.get("seen") == nil)
+            torustiq.state.set("seen", {ids = {"a", "b"}})
+            torustiq.state.set("tmp", "x")
+            torustiq.state.delete("tmp")
+            assert(torustiq.state.incr("count") == 1)
+            assert(torustiq.state.incr("count", 5) == 6)
+            assert(not pcall(torustiq.state.incr, "seen"))
+            assert(not pcall(torustiq.state.set, "f", print))
+        "#).unwrap();
+        cfg.state.flush().unwrap();
+
+        // Another Lua state with the state loaded from file
+        let cfg = LuaEnvConfig {
+            state: Arc::new(StateStore::open(path.clone()).unwrap()),
+            ..Default::default()
+        };
+        let lua = LuaEnv::try_new(&cfg).unwrap();
+        lua.exec_code(r#"
+            assert(torustiq.state.get("seen").ids[2] == "b")
+            assert(torustiq.state.get("count") == 6)
+            assert(torustiq.state.get("tmp") == nil)
+        "#).unwrap();
+
+        fs::write(&path, "not json").unwrap();
+        assert!(StateStore::open(path.clone()).is_err());
+    }
+
+    #[test]
+    fn test_flusher() {
+        let dir = TempDir::new("state_flusher");
+        let path = dir.join("state.json");
+        let store = Arc::new(StateStore::from_step_params(&to_hashmap(&[
+            ("state_path", path.to_str().unwrap()),
+            ("state_flush_interval_ms", "20"),
+        ])).unwrap());
+        start_flusher(&store);
+
+        // The state is written without an explicit flush, e.g. if the host is killed afterwards
+        store.set("count", Value::from(1));
+        thread::sleep(Duration::from_millis(200));
+        let saved: HashMap<String, Value> = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(saved.get("count"), Some(&Value::from(1)));
+
+        store.delete("count");
+        thread::sleep(Duration::from_millis(200));
+        let saved: HashMap<String, Value> = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert!(saved.is_empty());
+    }
+
+    #[test]
+    fn test_from_step_params() {
+        assert!(StateStore::from_step_params(&to_hashmap(&[])).is_ok());
+        assert!(StateStore::from_step_params(&to_hashmap(&[
+            ("state_path", "/tmp/state.json"),
+            ("state_flush_interval_ms", "0"),
+        ])).is_err());
+        assert!(StateStore::from_step_params(&to_hashmap(&[
+            ("state_path", "/tmp/state.json"),
+            ("state_flush_interval_ms", "soon"),
+        ])).is_err());
+    }
+}
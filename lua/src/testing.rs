@@ -0,0 +1,170 @@
+//! Runs Lua processors without a pipeline, e.g. to test scripts in CI. See the 'torustiq-lua-test' binary.
+//! Records are read from and written to JSON lines like {"content": "...", "metadata": {"k": "v"}}.
+//! Binary content is written as "content_base64" instead of "content"
+
+use std::{
+    collections::HashMap,
+    fs,
+    sync::{Arc, Mutex},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::{json, Map, Value};
+
+pub use crate::lua_env::OutputRecord;
+use crate::{
+    batch::BatchConfig,
+    lua_env::{LuaEnv, LuaEnvConfig, RecordSink},
+};
+
+/// Handle of the step passed to scripts in tests
+const TEST_MODULE_HANDLE: u32 = 0;
+
+/// Processes input records with the Lua code and returns all emitted records in order they were emitted:
+/// sent with torustiq_send or torustiq.send, returned from processor and from the 'shutdown' function of module.
+/// Step params are passed to the script like in a pipeline, including 'batch_size'. 'on_tick' is not called
+pub fn run_script(code: &[u8], params: HashMap<String, String>, input: Vec<OutputRecord>) -> Result<Vec<OutputRecord>, String> {
+    let captured = Arc::new(Mutex::new(vec![]));
+    let mut env_cfg = LuaEnvConfig::from_step_params(TEST_MODULE_HANDLE, params)?;
+    env_cfg.sink = RecordSink::Capture(captured.clone());
+    let batch_cfg = BatchConfig::from_step_params(&env_cfg.params)?;
+
+    let mut lua = LuaEnv::try_new(&env_cfg)?;
+    lua.load_processor(code)?;
+    lua.init_processor(&env_cfg.params)?;
+
+    let sink = env_cfg.sink.clone();
+    let emit = |records: Vec<OutputRecord>| -> Result<(), String> {
+        records.into_iter().try_for_each(|r| sink.send(TEST_MODULE_HANDLE, r))
+    };
+    match batch_cfg {
+        Some(b) => for (i, batch) in input.chunks(b.size).enumerate() {
+            let records = lua.call_batch_processor(batch)
+                .map_err(|e| format!("Failed to process the batch starting at record #{}: {}", i * b.size + 1, e))?;
+            emit(records)?;
+        },
+        None => for (i, record) in input.into_iter().enumerate() {
+            let records = lua.call_processor(TEST_MODULE_HANDLE, &record.content, record.metadata)
+                .map_err(|e| format!("Failed to process record #{}: {}", i + 1, e))?;
+            emit(records)?;
+        },
+    }
+    emit(lua.shutdown_processor()?)?;
+
+    let output = match captured.lock() {
+        Ok(mut r) => std::mem::take(&mut *r),
+        Err(e) => return Err(format!("Failed to read the captured records: {}", e)),
+    };
+    Ok(output)
+}
+
+/// Reads records from a JSON lines file. Empty lines are skipped
+pub fn read_records(path: &str) -> Result<Vec<OutputRecord>, String> {
+    let data = fs::read_to_string(path).map_err(|e| format!("Failed to read '{}': {}", path, e))?;
+    data.lines().enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| record_from_json(line).map_err(|e| format!("{}:{}: {}", path, i + 1, e)))
+        .collect()
+}
+
+pub fn record_from_json(line: &str) -> Result<OutputRecord, String> {
+    let value: Value = serde_json::from_str(line).map_err(|e| format!("Invalid JSON: {}", e))?;
+    let content = match (value.get("content"), value.get("content_base64")) {
+        (Some(Value::String(s)), None) => s.as_bytes().to_vec(),
+        (None, Some(Value::String(s))) => STANDARD.decode(s).map_err(|e| format!("Invalid 'content_base64': {}", e))?,
+        _ => return Err(String::from("A record must have either 'content' or 'content_base64' string field")),
+    };
+    let metadata = match value.get("metadata") {
+        None => HashMap::new(),
+        Some(Value::Object(fields)) => fields.iter()
+            // Typed values are compared in the same form as Lua scripts emit them
+            .map(|(k, v)| (k.clone(), match v {
+                Value::String(s) => s.clone(),
+                v => v.to_string(),
+            }))
+            .collect(),
+        Some(_) => return Err(String::from("'metadata' must be an object")),
+    };
+    Ok(OutputRecord { content, metadata })
+}
+
+pub fn record_to_json(record: &OutputRecord) -> String {
+    let mut value = Map::new();
+    match std::str::from_utf8(&record.content) {
+        Ok(s) => value.insert(String::from("content"), json!(s)),
+        Err(_) => value.insert(String::from("content_base64"), json!(STANDARD.encode(&record.content))),
+    };
+    // Keys are sorted, so the output is stable
+    let metadata: Map<String, Value> = record.metadata.iter().map(|(k, v)| (k.clone(), json!(v))).collect();
+    value.insert(String::from("metadata"), Value::Object(metadata));
+    Value::Object(value).to_string()
+}
+
+/// Returns an error describing the first difference between emitted and expected records
+pub fn compare_records(actual: &[OutputRecord], expected: &[OutputRecord]) -> Result<(), String> {
+    for (i, (a, e)) in actual.iter().zip(expected).enumerate() {
+        if a != e {
+            return Err(format!("Record #{} differs\n  expected: {}\n  actual:   {}",
+                i + 1, record_to_json(e), record_to_json(a)));
+        }
+    }
+    if actual.len() != expected.len() {
+        return Err(format!("Expected {} record(s), got {}", expected.len(), actual.len()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{compare_records, record_from_json, record_to_json, run_script};
+
+    #[test]
+    fn test_run_script() {
+        let code = r#"
+            local total = 0
+            return {
+                process = function (record)
+                    total = total + tonumber(record.content)
+                    torustiq.send({content = "seen " .. record.content})
+                    return {content = record.content, metadata = {prefix = torustiq.params.prefix}}
+                end,
+                shutdown = function ()
+                    return {content = "total " .. total}
+                end,
+            }
+        "#;
+        let params = HashMap::from([(String::from("prefix"), String::from("p"))]);
+        let input = vec![
+            record_from_json(r#"{"content": "1"}"#).unwrap(),
+            record_from_json(r#"{"content": "2", "metadata": {"k": "v"}}"#).unwrap(),
+        ];
+        let expected: Vec<_> = [
+            r#"{"content": "seen 1"}"#,
+            r#"{"content": "1", "metadata": {"prefix": "p"}}"#,
+            r#"{"content": "seen 2"}"#,
+            r#"{"content": "2", "metadata": {"prefix": "p"}}"#,
+            r#"{"content": "total 3"}"#,
+        ].iter().map(|l| record_from_json(l).unwrap()).collect();
+
+        let output = run_script(code.as_bytes(), params.clone(), input.clone()).unwrap();
+        assert_eq!(compare_records(&output, &expected), Ok(()));
+        assert!(compare_records(&output[1..], &expected).is_err());
+        assert!(compare_records(&output[..4], &expected).is_err());
+
+        let err = run_script(b"function (h, c, m) error('boom') end", params, input).unwrap_err();
+        assert!(err.contains("record #1") && err.contains("boom"), "Unexpected error: {}", err);
+    }
+
+    #[test]
+    fn test_record_json() {
+        let record = record_from_json(r#"{"content_base64": "AP8=", "metadata": {"a": "b"}}"#).unwrap();
+        assert_eq!(record.content, [0x00, 0xff]);
+        assert_eq!(record_to_json(&record), r#"{"content_base64":"AP8=","metadata":{"a":"b"}}"#);
+        assert!(record_from_json(r#"{"metadata": {}}"#).is_err());
+        let record = record_from_json(r#"{"content": "x", "metadata": {"n": 1, "ok": true}}"#).unwrap();
+        assert_eq!(record.metadata["n"], "1");
+        assert_eq!(record.metadata["ok"], "true");
+    }
+}
@@ -1,25 +1,53 @@
-use std::time::Duration;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, TryRecvError},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
-use log::error;
+use log::{error, info, warn};
 
 use torustiq_common::{
     ffi::types::module as module_types,
     pipeline::async_process
 };
 
-use crate::lua_env::LuaEnv;
+use crate::{
+    batch::{Batch, BatchConfig},
+    error_policy::ErrorPolicy,
+    lua_env::{send_record, LuaEnv, LuaEnvConfig, OutputRecord},
+    state::start_flusher,
+    watch::FileWatcher,
+    workers::{Dispatcher, WorkerPoolConfig},
+};
+
+/// Name of metadata field with the error message, see ErrorPolicy::ForwardOriginal
+const LUA_ERROR_METADATA_FIELD: &str = "lua.error";
+/// How often the processor checks if the step is requested to stop
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
 
 pub struct ThreadArgs {
-    pub code: String,
+    /// Lua source or bytecode
+    pub code: Vec<u8>,
     pub lib_args: module_types::LibCommonInitArgs,
     pub module_args: module_types::ModulePipelineConfigureArgs,
+    pub env_cfg: LuaEnvConfig,
+    pub pool_cfg: WorkerPoolConfig,
+    pub error_policy: ErrorPolicy,
+    /// Records are passed to the 'process_batch' function of module if set
+    pub batch_cfg: Option<BatchConfig>,
+    /// A file to reload the processor code from once it is changed
+    pub watch_file: Option<String>,
 }
 
 /// If module is source, this thread is called.
 /// Typically source code is some loop which produces new records
 /// and emits them to the next step
 pub fn thread_source(args: ThreadArgs) {
-    let lua = match LuaEnv::try_new() {
+    let lua = match LuaEnv::try_new(&args.env_cfg) {
         Ok(l) => l,
         Err(e) => {
             error!("Failed to create a Lua env in step '{}': {}", args.module_args.module_handle, e);
@@ -27,62 +55,347 @@ pub fn thread_source(args: ThreadArgs) {
             return
         },
     };
+    start_flusher(&args.env_cfg.state);
 
-    // Launcher code: looks up the 'run(module_handle)' Lua function
-    let code = format!("{}\nrun({})", args.code, args.module_args.module_handle);
-    if let Err(e) = lua.exec_code(code) {
+    // Launcher code: looks up the 'run(module_handle)' Lua function.
+    // The handle is also available as torustiq.handle
+    let launcher = format!("run({})", args.module_args.module_handle);
+    if let Err(e) = lua.exec_code(&args.code).and_then(|_| lua.exec_code(launcher)) {
         error!("An error occurred in Lua sender code: {}", e)
     };
+    flush_state(&args.env_cfg);
     (args.lib_args.on_step_terminate_cb)(args.module_args.module_handle);
 }
 
 /// Processor thread is started if the module is transformation or destination.
 /// An example of simple Lua code for processor:
-/// 
+///
 /// function (module_handle, content, metadata)
-///   content = content .. " And hello again from Lua file!!"
 ///   metadata["e"] = "fgh"
-///   torustiq_send(module_handle, content, metadata)
-///   return ""
+///   return {content = content .. " And hello again from Lua file!!", metadata = metadata}
 /// end
+///
+/// The function returns nil to drop the record, a record or an array of records.
+/// Calling torustiq_send(module_handle, content, metadata) instead is supported as well.
+/// The code might also return a module table with lifecycle functions, see lua_env::ScriptModule
 pub fn thread_processor(args: ThreadArgs) {
-    {
-        let lua = match LuaEnv::try_new() {
-            Ok(l) => l,
-            Err(e) => {
-                error!("Failed to create a Lua env in step '{}': {}", args.module_args.module_handle, e);
-                (args.lib_args.on_step_terminate_cb)(args.module_args.module_handle);
-                return
+    let module_handle = args.module_args.module_handle;
+    let rx = match async_process::get_receiver_owned(module_handle) {
+        Some(r) => r,
+        None => {
+            error!("Record receiver is not registered for step '{}'", module_handle);
+            (args.lib_args.on_step_terminate_cb)(module_handle);
+            return
+        }
+    };
+    start_flusher(&args.env_cfg.state);
+
+    if args.pool_cfg.workers == 1 {
+        if let Err(e) = run_worker(&args.code, &args.env_cfg, &args.error_policy, args.batch_cfg.clone(),
+            args.watch_file.clone(), Some(&args.env_cfg.stop_signal), rx) {
+            error!("Lua processor in step '{}' failed: {}", module_handle, e);
+        }
+    } else {
+        run_worker_pool(&args, rx);
+    }
+    flush_state(&args.env_cfg);
+    (args.lib_args.on_step_terminate_cb)(module_handle);
+}
+
+/// Writes the state of step to the file once the processing is finished.
+/// While the step runs, the changed state is written periodically, see state::start_flusher
+fn flush_state(env_cfg: &LuaEnvConfig) {
+    if let Err(e) = env_cfg.state.flush() {
+        error!("Failed to save the state of step '{}': {}", env_cfg.module_handle, e);
+    }
+}
+
+/// Creates a Lua env with initialized processor. In the batch mode the processor must handle batches
+fn load_processor(code: &[u8], env_cfg: &LuaEnvConfig, batch_mode: bool) -> Result<LuaEnv, String> {
+    let mut lua = LuaEnv::try_new(env_cfg).map_err(|e| format!("Failed to create a Lua env: {}", e))?;
+    lua.load_processor(code)?;
+    if batch_mode && !lua.supports_batches() {
+        return Err(String::from("'batch_size' requires a module with 'process_batch' function"));
+    }
+    if !batch_mode && !lua.supports_single_records() {
+        return Err(String::from("Module has no 'process' function. Set 'batch_size' to use 'process_batch'"));
+    }
+    lua.init_processor(&env_cfg.params)
+        .map_err(|e| format!("Failed to initialize a Lua module: {}", e))?;
+    Ok(lua)
+}
+
+/// Processes records with a dedicated Lua state until the receiver is disconnected
+/// or the stop signal is set and the queued records are processed.
+/// Returns an error if the step must be stopped
+fn run_worker(code: &[u8], env_cfg: &LuaEnvConfig, error_policy: &ErrorPolicy, batch_cfg: Option<BatchConfig>,
+    watch_file: Option<String>, stop_signal: Option<&AtomicBool>, rx: Receiver<module_types::Record>) -> Result<(), String> {
+    let module_handle = env_cfg.module_handle;
+    let mut lua = load_processor(code, env_cfg, batch_cfg.is_some())?;
+    let mut watcher = watch_file.map(FileWatcher::new);
+    let mut batch = batch_cfg.map(Batch::new);
+
+    let tick_interval = env_cfg.tick_interval;
+    let mut last_tick = Instant::now();
+    loop {
+        let mut timeout = tick_interval.saturating_sub(last_tick.elapsed());
+        if let Some(w) = &watcher {
+            timeout = timeout.min(w.time_to_next_check());
+        }
+        if let Some(deadline) = batch.as_ref().and_then(Batch::time_to_deadline) {
+            timeout = timeout.min(deadline);
+        }
+        if stop_signal.is_some() {
+            timeout = timeout.min(STOP_CHECK_INTERVAL);
+        }
+        let result = match (receive(&rx, timeout, stop_signal), batch.as_mut()) {
+            (Ok(in_record), Some(b)) => {
+                b.push(OutputRecord {
+                    content: in_record.content.to_byte_vec(),
+                    metadata: in_record.get_metadata_as_hashmap(),
+                });
+                Ok(())
             },
+            (Ok(in_record), None) => process_record(&lua, error_policy, &env_cfg.stop_signal, module_handle, in_record),
+            (Err(RecvTimeoutError::Timeout), _) => Ok(()),
+            (Err(RecvTimeoutError::Disconnected), _) => break,
         };
+        let result = result.and_then(|_| match batch.as_mut() {
+            Some(b) if b.is_ready() => process_batch(&lua, error_policy, &env_cfg.stop_signal, module_handle, b.take()),
+            _ => Ok(()),
+        });
+        if let Err(e) = result {
+            send_output_records(module_handle, lua.shutdown_processor());
+            return Err(e);
+        }
+
+        if last_tick.elapsed() >= tick_interval {
+            last_tick = Instant::now();
+            send_output_records(module_handle, lua.tick_processor());
+        }
 
-        let rx = match async_process::get_receiver_owned(args.module_args.module_handle) {
-            Some(r) => r,
-            None => {
-                error!("Record receiver is not registered for step '{}'", args.module_args.module_handle);
-                (args.lib_args.on_step_terminate_cb)(args.module_args.module_handle);
-                return
+        // The new code is swapped in between records. The previous version stays active if the new one fails to load
+        if let Some(w) = watcher.as_mut() {
+            match w.check().map(|code| code.and_then(|c| load_processor(&c, env_cfg, batch.is_some()))) {
+                Some(Ok(new_lua)) => {
+                    // The records collected so far are processed by the previous version
+                    if let Some(b) = batch.as_mut() {
+                        process_batch(&lua, error_policy, &env_cfg.stop_signal, module_handle, b.take())?;
+                    }
+                    send_output_records(module_handle, lua.shutdown_processor());
+                    lua = new_lua;
+                    info!("Reloaded the Lua code from '{}' in step '{}'", w.get_path(), module_handle);
+                },
+                Some(Err(e)) => error!("Failed to reload the Lua code from '{}' in step '{}'. \
+                    The previous version stays active: {}", w.get_path(), module_handle, e),
+                None => {},
             }
-        };
+        }
+    }
+
+    let result = match batch.as_mut() {
+        Some(b) => process_batch(&lua, error_policy, &env_cfg.stop_signal, module_handle, b.take()),
+        None => Ok(()),
+    };
+    send_output_records(module_handle, lua.shutdown_processor());
+    result
+}
+
+/// Waits for the next record. Once the stop signal is set, returns the queued records without waiting
+/// and reports the receiver as disconnected when the queue is empty
+fn receive<T>(rx: &Receiver<T>, timeout: Duration, stop_signal: Option<&AtomicBool>) -> Result<T, RecvTimeoutError> {
+    if !stop_signal.is_some_and(|s| s.load(Ordering::SeqCst)) {
+        return rx.recv_timeout(timeout)
+    }
+    rx.try_recv().map_err(|e| match e {
+        TryRecvError::Empty | TryRecvError::Disconnected => RecvTimeoutError::Disconnected,
+    })
+}
+
+/// Processes a record and handles errors according to the error policy.
+/// Returns an error if the step must be stopped
+fn process_record(lua: &LuaEnv, error_policy: &ErrorPolicy, stop_signal: &AtomicBool,
+    module_handle: module_types::ModuleHandle, record: module_types::Record) -> Result<(), String> {
+    let record = OutputRecord {
+        content: record.content.to_byte_vec(),
+        metadata: record.get_metadata_as_hashmap(),
+    };
+    process_with_policy(error_policy, stop_signal, module_handle, vec![record],
+        |r| lua.call_processor(module_handle, &r[0].content, r[0].metadata.clone()))
+}
+
+/// Processes a batch of records with the 'process_batch' function. The error policy is applied to the whole batch
+fn process_batch(lua: &LuaEnv, error_policy: &ErrorPolicy, stop_signal: &AtomicBool,
+    module_handle: module_types::ModuleHandle, records: Vec<OutputRecord>) -> Result<(), String> {
+    if records.is_empty() {
+        return Ok(())
+    }
+    process_with_policy(error_policy, stop_signal, module_handle, records, |r| lua.call_batch_processor(r))
+}
 
-        let process_func = match lua.create_function_from_code(args.code) {
-            Ok(f) => f,
-            Err(e) => {
-                error!("Failed to create a Lua function in step '{}': {}", args.module_args.module_handle, e);
-                (args.lib_args.on_step_terminate_cb)(args.module_args.module_handle);
-                return
+/// Calls the processor with input records and retries, drops or forwards them on error.
+/// The final error is logged. Retries are given up once the step is stopped.
+/// Returns an error if the step must be stopped
+fn process_with_policy<F>(error_policy: &ErrorPolicy, stop_signal: &AtomicBool, module_handle: module_types::ModuleHandle,
+    records: Vec<OutputRecord>, call: F) -> Result<(), String>
+    where F: Fn(&[OutputRecord]) -> Result<Vec<OutputRecord>, String> {
+    let mut attempt = 1;
+    let err = loop {
+        let err = match call(&records) {
+            Ok(out_records) => {
+                send_output_records(module_handle, Ok(out_records));
+                return Ok(())
             },
+            Err(e) => e,
         };
+        match error_policy.retry_delay(attempt) {
+            Some(delay) => {
+                warn!("Attempt {} to process {} record(s) in step '{}' failed: {}. Retrying in {:?}",
+                    attempt, records.len(), module_handle, err, delay);
+                if !sleep_unless_stopped(delay, stop_signal) {
+                    warn!("Step '{}' is stopped. No more attempts to process {} record(s)", module_handle, records.len());
+                    break err;
+                }
+                attempt += 1;
+            },
+            None => break err,
+        }
+    };
+
+    match error_policy {
+        ErrorPolicy::Drop | ErrorPolicy::Retry { .. } => {
+            error!("Dropped {} record(s) in step '{}' after {} attempt(s): {}", records.len(), module_handle, attempt, err);
+            Ok(())
+        },
+        ErrorPolicy::ForwardOriginal => {
+            error!("Forwarding {} original record(s) in step '{}' after error: {}", records.len(), module_handle, err);
+            let records = records.into_iter()
+                .map(|mut r| {
+                    r.metadata.insert(String::from(LUA_ERROR_METADATA_FIELD), err.clone());
+                    r
+                })
+                .collect();
+            send_output_records(module_handle, Ok(records));
+            Ok(())
+        },
+        ErrorPolicy::Terminate => Err(format!("Stopping the step after error: {}", err)),
+    }
+}
+
+/// Sleeps in short slices, so a stopped step doesn't wait for the whole delay.
+/// Returns false if the stop signal is set
+fn sleep_unless_stopped(delay: Duration, stop_signal: &AtomicBool) -> bool {
+    let deadline = Instant::now() + delay;
+    loop {
+        if stop_signal.load(Ordering::SeqCst) {
+            return false
+        }
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return true
+        }
+        thread::sleep(left.min(STOP_CHECK_INTERVAL));
+    }
+}
 
-        loop {
-            let in_record: module_types::Record = match rx.recv_timeout(Duration::from_secs(1)) {
-                Ok(r) => r,
-                Err(_) => continue, // timeout
-            }.into();
-            
-            if let Err(e) = lua.call_process_record_function(&process_func, args.module_args.module_handle, in_record) {
-                error!("ERROR: {}", e);
+/// Starts a worker thread with its own Lua state per each worker and distributes records between them.
+/// Lua states are not shared, so each worker has own global variables.
+/// Workers stop once the dispatcher is stopped and they have processed the records sent to them
+fn run_worker_pool(args: &ThreadArgs, rx: Receiver<module_types::Record>) {
+    let module_handle = args.module_args.module_handle;
+    let mut senders = vec![];
+    let mut worker_threads = vec![];
+    // Set by a failed worker to stop the whole step
+    let worker_failed = Arc::new(AtomicBool::new(false));
+    for i in 0..args.pool_cfg.workers {
+        let (tx, worker_rx) = mpsc::channel();
+        let code = args.code.clone();
+        let env_cfg = args.env_cfg.clone();
+        let error_policy = args.error_policy.clone();
+        let batch_cfg = args.batch_cfg.clone();
+        let watch_file = args.watch_file.clone();
+        let worker_failed = worker_failed.clone();
+        worker_threads.push(thread::spawn(move || {
+            if let Err(e) = run_worker(&code, &env_cfg, &error_policy, batch_cfg, watch_file, None, worker_rx) {
+                error!("Lua worker #{} in step '{}' failed: {}", i, module_handle, e);
+                worker_failed.store(true, Ordering::SeqCst);
             }
+        }));
+        senders.push(tx);
+    }
+
+    let mut dispatcher = Dispatcher::new(args.pool_cfg.clone());
+    while !worker_failed.load(Ordering::SeqCst) {
+        let record = match receive(&rx, STOP_CHECK_INTERVAL, Some(&args.env_cfg.stop_signal)) {
+            Ok(r) => r,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let worker = dispatcher.pick_worker_for_record(&record);
+        if senders[worker].send(record).is_err() {
+            error!("Lua worker #{} in step '{}' is not running. Stopping the step", worker, module_handle);
+            break
+        }
+    }
+
+    // Workers process the remaining records and stop once the senders are dropped
+    drop(senders);
+    for t in worker_threads {
+        if t.join().is_err() {
+            error!("A Lua worker thread in step '{}' panicked", module_handle);
         }
     }
-}
\ No newline at end of file
+}
+
+/// Passes the records emitted by Lua code to the next step. Errors are logged: records are processed
+/// asynchronously, so the host has no way to receive them
+fn send_output_records(module_handle: module_types::ModuleHandle, records: Result<Vec<OutputRecord>, String>) {
+    let records = match records {
+        Ok(r) => r,
+        Err(e) => {
+            error!("ERROR: {}", e);
+            return
+        },
+    };
+    for record in records {
+        if let Err(e) = send_record(module_handle, record) {
+            error!("Failed to send a record from step '{}': {}", module_handle, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{atomic::AtomicBool, mpsc},
+        time::{Duration, Instant},
+    };
+
+    use super::{receive, sleep_unless_stopped};
+
+    #[test]
+    fn test_receive_drains_queue_on_stop() {
+        let (tx, rx) = mpsc::channel();
+        let stop_signal = AtomicBool::new(false);
+        tx.send(1).unwrap();
+        tx.send(2).unwrap();
+        assert_eq!(receive(&rx, Duration::ZERO, Some(&stop_signal)), Ok(1));
+
+        stop_signal.store(true, std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(receive(&rx, Duration::ZERO, Some(&stop_signal)), Ok(2));
+        // The sender is still connected, but the queue is drained
+        assert_eq!(receive(&rx, Duration::ZERO, Some(&stop_signal)), Err(mpsc::RecvTimeoutError::Disconnected));
+        assert_eq!(receive(&rx, Duration::ZERO, None), Err(mpsc::RecvTimeoutError::Timeout));
+    }
+
+    #[test]
+    fn test_sleep_unless_stopped() {
+        let stop_signal = AtomicBool::new(false);
+        assert!(sleep_unless_stopped(Duration::from_millis(10), &stop_signal));
+
+        stop_signal.store(true, std::sync::atomic::Ordering::SeqCst);
+        let started = Instant::now();
+        assert!(!sleep_unless_stopped(Duration::from_secs(60), &stop_signal));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
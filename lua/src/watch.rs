@@ -0,0 +1,91 @@
+use std::{
+    fs,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// How often the modification time of file is checked
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Detects changes of script file by its modification time
+pub struct FileWatcher {
+    path: String,
+    modified: Option<SystemTime>,
+    last_check: Instant,
+}
+
+impl FileWatcher {
+    pub fn new(path: String) -> Self {
+        FileWatcher {
+            modified: get_modified_time(&path),
+            path,
+            last_check: Instant::now(),
+        }
+    }
+
+    pub fn get_path(&self) -> &str {
+        &self.path
+    }
+
+    /// Returns the time left until the next check
+    pub fn time_to_next_check(&self) -> Duration {
+        WATCH_INTERVAL.saturating_sub(self.last_check.elapsed())
+    }
+
+    /// Returns new contents of file if it was modified since the previous check.
+    /// The check is skipped if the previous one was less than a second ago
+    pub fn check(&mut self) -> Option<Result<Vec<u8>, String>> {
+        if self.time_to_next_check() > Duration::ZERO {
+            return None;
+        }
+        self.check_now()
+    }
+
+    fn check_now(&mut self) -> Option<Result<Vec<u8>, String>> {
+        self.last_check = Instant::now();
+        // The file might be missing for a moment while an editor replaces it
+        let modified = get_modified_time(&self.path)?;
+        if Some(modified) == self.modified {
+            return None;
+        }
+        self.modified = Some(modified);
+        Some(fs::read(&self.path)
+            .map_err(|e| format!("Failed to read contents of Lua file '{}': {}", self.path, e)))
+    }
+}
+
+fn get_modified_time(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, File},
+        time::{Duration, SystemTime},
+    };
+
+    use crate::test_utils::TempDir;
+
+    use super::FileWatcher;
+
+    #[test]
+    fn test_check() {
+        let dir = TempDir::new("watch");
+        let path = dir.join("script.lua");
+        fs::write(&path, "return 1").unwrap();
+        let mut watcher = FileWatcher::new(path.to_str().unwrap().to_string());
+        assert_eq!(watcher.check_now(), None);
+
+        fs::write(&path, "return 2").unwrap();
+        // Modification time might have a low resolution on some file systems
+        File::options().write(true).open(&path).unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        assert_eq!(watcher.check_now(), Some(Ok(b"return 2".to_vec())));
+        assert_eq!(watcher.check_now(), None);
+        // Too early for the next check
+        assert_eq!(watcher.check(), None);
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(watcher.check_now(), None);
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+};
+
+use torustiq_common::ffi::types::module::Record;
+
+/// How records are distributed between workers
+#[derive(Clone, Debug, PartialEq)]
+pub enum Ordering {
+    /// Records are distributed evenly, the order is not preserved
+    None,
+    /// Records with the same value of metadata field are processed by the same worker in order they arrive
+    PerKey(String),
+}
+
+/// A pool of independent Lua states which process records in parallel
+#[derive(Clone, Debug, PartialEq)]
+pub struct WorkerPoolConfig {
+    pub workers: usize,
+    pub ordering: Ordering,
+}
+
+impl Default for WorkerPoolConfig {
+    fn default() -> Self {
+        WorkerPoolConfig {
+            workers: 1,
+            ordering: Ordering::None,
+        }
+    }
+}
+
+impl WorkerPoolConfig {
+    /// Reads the worker pool settings from step parameters:
+    /// - workers: number of Lua states, 1 by default
+    /// - ordering: 'none' (default) or 'per_key'
+    /// - ordering.key: metadata field with the key. Required if ordering is 'per_key'
+    pub fn from_step_params(params: &HashMap<String, String>) -> Result<Self, String> {
+        let workers = match params.get("workers") {
+            Some(v) => v.parse::<usize>().map_err(|e| format!("Invalid value of 'workers': '{}': {}", v, e))?,
+            None => 1,
+        };
+        if workers == 0 {
+            return Err(String::from("'workers' must be greater than zero"));
+        }
+
+        let ordering = match params.get("ordering").map(String::as_str) {
+            None | Some("none") => Ordering::None,
+            Some("per_key") => match params.get("ordering.key") {
+                Some(k) => Ordering::PerKey(k.clone()),
+                None => return Err(String::from("'ordering.key' must be set if ordering is 'per_key'")),
+            },
+            Some(v) => return Err(format!("Invalid value of 'ordering': '{}'. Expected 'none' or 'per_key'", v)),
+        };
+        Ok(WorkerPoolConfig {
+            workers,
+            ordering,
+        })
+    }
+}
+
+/// Picks a worker for each record
+pub struct Dispatcher {
+    cfg: WorkerPoolConfig,
+    next_worker: usize,
+}
+
+impl Dispatcher {
+    pub fn new(cfg: WorkerPoolConfig) -> Self {
+        Dispatcher {
+            cfg,
+            next_worker: 0,
+        }
+    }
+
+    /// Returns an index of worker for record
+    pub fn pick_worker_for_record(&mut self, record: &Record) -> usize {
+        let key = match &self.cfg.ordering {
+            Ordering::PerKey(field) => record.get_metadata_as_hashmap().remove(field),
+            Ordering::None => None,
+        };
+        self.pick_worker(key)
+    }
+
+    /// Returns an index of worker for record with provided key.
+    /// Records without the key are distributed like with no ordering
+    fn pick_worker(&mut self, key: Option<String>) -> usize {
+        if let Some(key) = key {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            return (hasher.finish() % self.cfg.workers as u64) as usize;
+        }
+        let worker = self.next_worker;
+        self.next_worker = (self.next_worker + 1) % self.cfg.workers;
+        worker
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::test_utils::to_hashmap;
+
+    use super::{Dispatcher, Ordering, WorkerPoolConfig};
+
+    #[test]
+    fn test_from_step_params() {
+        assert_eq!(WorkerPoolConfig::from_step_params(&HashMap::new()), Ok(WorkerPoolConfig::default()));
+        assert_eq!(WorkerPoolConfig::from_step_params(&to_hashmap(&[
+            ("workers", "4"),
+            ("ordering", "per_key"),
+            ("ordering.key", "kafka.key"),
+        ])), Ok(WorkerPoolConfig {
+            workers: 4,
+            ordering: Ordering::PerKey(String::from("kafka.key")),
+        }));
+        assert!(WorkerPoolConfig::from_step_params(&to_hashmap(&[("workers", "0")])).is_err());
+        assert!(WorkerPoolConfig::from_step_params(&to_hashmap(&[("ordering", "per_key")])).is_err());
+        assert!(WorkerPoolConfig::from_step_params(&to_hashmap(&[("ordering", "total")])).is_err());
+    }
+
+    #[test]
+    fn test_pick_worker() {
+        let mut dispatcher = Dispatcher::new(WorkerPoolConfig {
+            workers: 3,
+            ordering: Ordering::None,
+        });
+        let picked: Vec<usize> = (0..4).map(|_| dispatcher.pick_worker(None)).collect();
+        assert_eq!(picked, vec![0, 1, 2, 0]);
+
+        let mut dispatcher = Dispatcher::new(WorkerPoolConfig {
+            workers: 8,
+            ordering: Ordering::PerKey(String::from("user")),
+        });
+        for user in ["alice", "bob", "carol"] {
+            let worker = dispatcher.pick_worker(Some(String::from(user)));
+            assert!(worker < 8);
+            assert!((0..10).all(|_| dispatcher.pick_worker(Some(String::from(user))) == worker));
+        }
+    }
+}